@@ -1,125 +1,265 @@
-mod chunk;
-mod tasks;
-
 use std::{
+    io::{self, Write},
     net::{IpAddr, SocketAddr},
     path::PathBuf,
-    str::FromStr,
-    sync::Arc,
+    process::{Command, ExitCode, ExitStatus, Stdio},
+    time::Duration,
 };
 
 use anyhow::{Error, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use ed25519_dalek::VerifyingKey;
 use env_logger::Env;
-use multicats::{
-    ImageMetadata, ServerDiscovery,
-    net::{NetworkInterface, get_interface},
+use log::{error, info};
+use multicats::client::{Client, ClientConfig, ClientProgress};
+use tokio::{
+    select,
+    sync::watch,
+    time::{Instant, sleep_until},
 };
-use socket2::InterfaceIndexOrAddress;
-use tokio::{sync::SetOnce, try_join};
-use tokio_util::sync::CancellationToken;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressFormat {
+    /// Human readable log lines on stderr.
+    Log,
+    /// One JSON object per line.
+    Json,
+}
 
 #[derive(Parser)]
 struct ClientArgs {
-    #[clap(long, default_value_t = SocketAddr::from_str("[ff18::1]:7890").unwrap())]
+    #[clap(long, default_value_t = ClientConfig::default().discovery_socket)]
     discovery_socket: SocketAddr,
     #[clap(long, short = 'f')]
     force: bool,
+    /// Leave holes in regular files instead of writing chunks of zeros.
+    #[clap(long)]
+    sparse: bool,
+    /// File or device to copy matching chunks from, can be repeated.
+    #[clap(long = "seed")]
+    seeds: Vec<PathBuf>,
+    /// Retrieve the image metadata over TCP instead of multicast.
+    #[clap(long)]
+    tcp_metadata: bool,
     #[clap(long)]
     interface: Option<String>,
     #[clap(long)]
     unicast_address: Option<IpAddr>,
-    #[clap(long, default_value_t = 1)]
+    #[clap(long, default_value_t = ClientConfig::default().hops)]
     hops: u32,
+    #[clap(long = "trusted-key", value_parser = parse_verifying_key)]
+    trusted_keys: Vec<VerifyingKey>,
+    #[clap(long, value_enum, default_value_t = ProgressFormat::Log)]
+    progress: ProgressFormat,
+    /// File descriptor JSON progress is written to instead of stdout.
+    #[clap(long)]
+    progress_fd: Option<i32>,
+    /// Bytes received ahead of the first missing chunk when writing to stdout.
+    #[clap(long, default_value_t = ClientConfig::default().reorder_buffer)]
+    reorder_buffer: u64,
+    /// Never send anything to the server, for one-way links.
+    #[clap(long)]
+    passive: bool,
+    /// Shell command run once the image is received and verified. A failure
+    /// of the command is the exit status of the client.
+    #[clap(long)]
+    on_complete: Option<String>,
+    /// Shell command run when the transfer fails.
+    #[clap(long)]
+    on_failure: Option<String>,
+    /// File, device or directory to write the image to, `-` to write it to
+    /// stdout in order.
     file: PathBuf,
 }
 
-struct ClientState {
-    token: CancellationToken,
-    interface: NetworkInterface,
-    interface_id: InterfaceIndexOrAddress,
-    unicast: IpAddr,
-    args: ClientArgs,
-    server: SetOnce<ServerDiscovery>,
-    image: SetOnce<ImageMetadata>,
+fn parse_verifying_key(s: &str) -> Result<VerifyingKey> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(s, &mut key)
+        .map_err(|_| Error::msg("Public key must be 32 hex encoded bytes."))?;
+    Ok(VerifyingKey::from_bytes(&key)?)
 }
 
-fn args_to_state(args: ClientArgs) -> Result<ClientState> {
-    if !args.discovery_socket.ip().is_multicast() {
-        return Err(Error::msg("Discovery address must be a multicast group."));
+fn progress_output(fd: Option<i32>) -> Result<Box<dyn Write + Send>> {
+    let Some(fd) = fd else {
+        return Ok(Box::new(io::stdout()));
+    };
+
+    #[cfg(unix)]
+    {
+        use std::{fs::File, os::fd::FromRawFd};
+        // SAFETY: the descriptor was handed to us by whoever spawned the
+        // client, nothing else in the process uses it.
+        Ok(Box::new(unsafe { File::from_raw_fd(fd) }))
     }
 
-    let Some(interface) = get_interface(args.interface.as_deref())? else {
-        return Err(Error::msg("Cannot find requested interface."));
-    };
+    #[cfg(not(unix))]
+    {
+        let _ = fd;
+        Err(Error::msg(
+            "Progress file descriptors are only supported on unix.",
+        ))
+    }
+}
 
-    let interface_id = if args.discovery_socket.is_ipv6() {
-        InterfaceIndexOrAddress::Index(interface.index)
+/// Runs a hook through the shell with details about the transfer in its
+/// environment. Its output goes to stderr when stdout carries the image.
+fn run_hook(
+    command: &str,
+    target: &str,
+    progress: &ClientProgress,
+    duration: Duration,
+    error: Option<&Error>,
+) -> Result<ExitStatus> {
+    let mut hook = if cfg!(windows) {
+        let mut hook = Command::new("cmd");
+        hook.arg("/C");
+        hook
     } else {
-        let address = interface
-            .ips
-            .iter()
-            .filter_map(|ip| match ip {
-                IpAddr::V4(ip) => Some(ip),
-                _ => None,
-            })
-            .next();
-        if let Some(&address) = address {
-            InterfaceIndexOrAddress::Address(address)
-        } else {
-            return Err(Error::msg(
-                "In IPv4 mode the selected interface needs to have at least one IPv4 address assigned to it.",
-            ));
-        }
+        let mut hook = Command::new("sh");
+        hook.arg("-c");
+        hook
     };
+    hook.arg(command)
+        .env("MULTICATS_TARGET", target)
+        .env("MULTICATS_DURATION", duration.as_secs_f64().to_string())
+        .env(
+            "MULTICATS_BYTES_RECEIVED",
+            progress.bytes_received.to_string(),
+        )
+        .env(
+            "MULTICATS_BYTES_WRITTEN",
+            progress.bytes_written.to_string(),
+        )
+        .env("MULTICATS_BYTES_TOTAL", progress.bytes_total.to_string());
+    if let Some(server) = progress.server {
+        hook.env("MULTICATS_SERVER", server.ip().to_string());
+    }
+    if let Some(digest) = &progress.digest {
+        hook.env("MULTICATS_DIGEST", digest);
+    }
+    if let Some(error) = error {
+        hook.env("MULTICATS_ERROR", error.to_string());
+    }
+    if target == "-" {
+        hook.stdout(Stdio::from(io::stderr()));
+    }
 
-    let unicast = match args.unicast_address {
-        Some(ip) => {
-            if ip.is_ipv6() != args.discovery_socket.is_ipv6() {
-                return Err(Error::msg(
-                    "Unicast address must be of the same family as the multicast groups.",
-                ));
-            }
-            ip
-        }
-        None => {
-            let Some(&x) = interface
-                .ips
-                .iter()
-                .find(|&ip| ip.is_ipv6() == args.discovery_socket.is_ipv6())
-            else {
-                return Err(Error::msg(
-                    "Cannot find any suitable unicast address on the selected interface.",
-                ));
-            };
-            x
+    info!("Running hook {}", command);
+    Ok(hook.status()?)
+}
+
+/// Writes a progress line every second and whenever the phase changes, plus a
+/// last one once the client stops.
+async fn json_progress(
+    mut progress: watch::Receiver<ClientProgress>,
+    mut out: Box<dyn Write + Send>,
+) -> Result<()> {
+    let mut last: Option<ClientProgress> = None;
+    let mut next = Instant::now();
+
+    loop {
+        let running = select! {
+            x = progress.changed() => x.is_ok(),
+            _ = sleep_until(next) => true,
+        };
+
+        let current = progress.borrow_and_update().clone();
+        let write = match &last {
+            None => true,
+            Some(last) if running => last.phase != current.phase || Instant::now() >= next,
+            Some(last) => *last != current,
+        };
+        if write {
+            serde_json::to_writer(&mut out, &current)?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+            last = Some(current);
+            next = Instant::now() + Duration::from_secs(1);
         }
-    };
 
-    Ok(ClientState {
-        token: CancellationToken::new(),
-        unicast,
-        interface_id,
-        interface,
-        args,
-        server: SetOnce::new(),
-        image: SetOnce::new(),
-    })
+        if !running {
+            return Ok(());
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
     let args = ClientArgs::parse();
 
-    let state = Arc::new(args_to_state(args)?);
+    let to_stdout = args.file.as_os_str() == "-";
+    if to_stdout && args.progress == ProgressFormat::Json && args.progress_fd.is_none() {
+        return Err(Error::msg(
+            "JSON progress needs a file descriptor when the image is written to stdout.",
+        ));
+    }
+
+    let target = args.file.display().to_string();
+    let builder = if to_stdout {
+        Client::output_builder(tokio::io::stdout())
+    } else {
+        Client::builder(args.file)
+    };
+
+    let client = builder
+        .discovery_socket(args.discovery_socket)
+        .force(args.force)
+        .sparse(args.sparse)
+        .seeds(args.seeds)
+        .tcp_metadata(args.tcp_metadata)
+        .interface(args.interface)
+        .unicast_address(args.unicast_address)
+        .hops(args.hops)
+        .trusted_keys(args.trusted_keys)
+        .reorder_buffer(args.reorder_buffer)
+        .passive(args.passive)
+        .build()?;
+
+    let reporter = match args.progress {
+        ProgressFormat::Log => None,
+        ProgressFormat::Json => Some(tokio::spawn(json_progress(
+            client.progress(),
+            progress_output(args.progress_fd)?,
+        ))),
+    };
+
+    let progress = client.progress();
+    let start = Instant::now();
+    let result = client.run().await;
+    let duration = start.elapsed();
 
-    let server_discovery = tasks::spawn(tasks::server_discovery(state.clone()));
-    let metadata_transfer = tasks::spawn(tasks::metadata_transfer(state.clone()));
-    let chunk_transfer = tasks::spawn(tasks::chunk_transfer(state.clone()));
+    if let Some(reporter) = reporter {
+        reporter.await??;
+    }
 
-    try_join!(server_discovery, metadata_transfer, chunk_transfer)?;
+    let hook = match &result {
+        Ok(()) => args.on_complete,
+        Err(_) => args.on_failure,
+    };
+    let Some(hook) = hook else {
+        return result.map(|_| ExitCode::SUCCESS);
+    };
+    let status = run_hook(
+        &hook,
+        &target,
+        &progress.borrow(),
+        duration,
+        result.as_ref().err(),
+    )?;
 
-    Ok(())
+    if status.success() {
+        return result.map(|_| ExitCode::SUCCESS);
+    }
+    // The transfer failing takes precedence over the failure hook.
+    if let Err(e) = result {
+        error!("Failure hook failed ({})", status);
+        return Err(e);
+    }
+    error!("Completion hook failed ({})", status);
+    Ok(status
+        .code()
+        .and_then(|code| u8::try_from(code).ok())
+        .map_or(ExitCode::FAILURE, ExitCode::from))
 }
@@ -1,128 +1,125 @@
-mod image;
-mod tasks;
-
 use std::{
+    fs, io,
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
-    str::FromStr,
-    sync::Arc,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{Error, Result};
 use clap::Parser;
+use ed25519_dalek::SigningKey;
 use env_logger::Env;
 use multicats::{
-    ImageMetadata,
-    net::{NetworkInterface, get_interface},
+    hash::HashAlgorithm,
+    server::{Scheduling, Server, ServerConfig},
 };
-use socket2::InterfaceIndexOrAddress;
-use tokio::{sync::SetOnce, try_join};
-use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 struct ServerArgs {
+    /// File or directory to serve, `-` to stream standard input.
     file: PathBuf,
-    #[clap(long, default_value_t = SocketAddr::from_str("[ff18::1]:7890").unwrap())]
+    /// Stream the file while reading it, e.g. a named pipe.
+    #[clap(long)]
+    stream: bool,
+    /// Chunks of a stream kept in memory for retransmission.
+    #[clap(long, default_value_t = ServerConfig::default().stream_window)]
+    stream_window: usize,
+    #[clap(long, default_value_t = ServerConfig::default().discovery_socket)]
     discovery_socket: SocketAddr,
-    #[clap(long, default_value_t = SocketAddr::from_str("[ff18::2]:7891").unwrap())]
+    #[clap(long, default_value_t = ServerConfig::default().transfer_socket)]
     transfer_socket: SocketAddr,
+    /// Group the image metadata is carouselled on, 239.255.0.3:7892 by default
+    /// with an IPv4 discovery socket.
+    #[clap(long, default_value_t = ServerConfig::default().metadata_group.unwrap())]
+    metadata_group: SocketAddr,
+    /// Only serve the image metadata over TCP.
+    #[clap(long)]
+    no_metadata_carousel: bool,
+    /// Rate of the metadata carousel in bits per second.
+    #[clap(long, default_value_t = ServerConfig::default().metadata_rate)]
+    metadata_rate: u32,
+    /// Only serve the image metadata over multicast.
+    #[clap(long)]
+    no_tcp_metadata: bool,
+    /// Have clients send their requests to this multicast group, where they
+    /// hear each other and skip requests already sent by another client.
+    #[clap(long)]
+    request_group: Option<SocketAddr>,
     #[clap(long)]
     unicast_address: Option<IpAddr>,
     #[clap(long)]
     interface: Option<String>,
-    #[clap(long, default_value_t = 1)]
+    #[clap(long, default_value_t = ServerConfig::default().max_hops)]
     max_hops: u32,
-    #[clap(long, default_value_t = 1000)]
+    #[clap(long, default_value_t = ServerConfig::default().discovery_interval.as_millis() as u64)]
     discovery_interval: u64,
-    #[clap(long, default_value_t = 5 * 1024 * 1024)]
+    #[clap(long, default_value_t = ServerConfig::default().chunk_size)]
     chunk_size: usize,
-    #[clap(long, default_value_t = 1500 - 40 - 8)]
+    /// Cut chunks at content-defined boundaries, chunk size becomes the largest size.
+    #[clap(long)]
+    content_defined_chunking: bool,
+    #[clap(long, default_value_t = ServerConfig::default().max_udp_payload_size)]
     max_udp_payload_size: u16,
-    #[clap(long, default_value_t = 1024 * 1024 * 1024)]
+    #[clap(long, default_value_t = ServerConfig::default().flood_speed)]
     flood_speed: u32,
+    #[clap(long)]
+    min_flood_speed: Option<u32>,
+    #[clap(long, default_value_t = ServerConfig::default().target_loss)]
+    target_loss: f32,
+    #[clap(long)]
+    drop_slow_after: Option<u64>,
+    #[clap(long)]
+    expect_clients: Option<usize>,
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+    #[clap(long)]
+    fec_overhead: Option<u32>,
+    /// Compress chunks with zstd at this level.
+    #[clap(long)]
+    compression_level: Option<i32>,
+    /// Load the image metadata from this file, or write it there if missing or
+    /// outdated.
+    #[clap(long)]
+    metadata_file: Option<PathBuf>,
+    /// Do not cache the image metadata next to the image.
+    #[clap(long)]
+    no_metadata_cache: bool,
+    /// Threads hashing the image, defaults to one per CPU.
+    #[clap(long)]
+    hash_threads: Option<usize>,
+    /// Bypass the page cache when hashing the image.
+    #[clap(long)]
+    direct_io: bool,
+    /// Send every chunk over and over at the flood speed, ignoring requests,
+    /// for clients on one-way links.
+    #[clap(long)]
+    carousel: bool,
+    /// Order in which requested chunks are sent.
+    #[clap(long, value_enum, default_value_t = Scheduling::default())]
+    scheduling: Scheduling,
+    /// Exit once the image metadata has been computed and saved.
+    #[clap(long)]
+    metadata_only: bool,
+    #[clap(long)]
+    signing_key: Option<PathBuf>,
+    /// Defaults to blake3 with a signing key, and to xxh3 otherwise.
+    #[clap(long, value_enum)]
+    hash: Option<HashAlgorithm>,
 }
 
-struct ServerState {
-    token: CancellationToken,
-    unicast: IpAddr,
-    interface: NetworkInterface,
-    interface_id: InterfaceIndexOrAddress,
-    metadata_socket: SetOnce<SocketAddr>,
-    request_socket: SetOnce<SocketAddr>,
-    image: ImageMetadata,
-    args: ServerArgs,
-}
-
-fn args_to_state(args: ServerArgs) -> Result<ServerState> {
-    if args.discovery_socket.is_ipv6() != args.transfer_socket.is_ipv6() {
-        return Err(Error::msg(
-            "Discovery and transfer sockets must be of the same family.",
-        ));
-    }
-
-    if !args.discovery_socket.ip().is_multicast() || !args.transfer_socket.ip().is_multicast() {
-        return Err(Error::msg(
-            "Discovery and transfer addresses must be multicast groups.",
-        ));
-    }
-
-    let Some(interface) = get_interface(args.interface.as_deref())? else {
-        return Err(Error::msg("Cannot find requested interface."));
-    };
-
-    let interface_id = if args.discovery_socket.is_ipv6() {
-        InterfaceIndexOrAddress::Index(interface.index)
-    } else {
-        let address = interface
-            .ips
-            .iter()
-            .filter_map(|ip| match ip {
-                IpAddr::V4(ip) => Some(ip),
-                _ => None,
-            })
-            .next();
-        if let Some(&address) = address {
-            InterfaceIndexOrAddress::Address(address)
-        } else {
-            return Err(Error::msg(
-                "In IPv4 mode the selected interface needs to have at least one IPv4 address assigned to it.",
-            ));
+/// Reads an Ed25519 secret key, stored either as 32 raw bytes or hex encoded.
+fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let data = fs::read(path)?;
+    let key: [u8; 32] = match data.as_slice().try_into() {
+        Ok(x) => x,
+        Err(_) => {
+            let mut key = [0u8; 32];
+            hex::decode_to_slice(data.trim_ascii(), &mut key)
+                .map_err(|_| Error::msg("Signing key must be 32 raw or hex encoded bytes."))?;
+            key
         }
     };
-
-    let unicast = match args.unicast_address {
-        Some(ip) => {
-            if ip.is_ipv6() != args.discovery_socket.is_ipv6() {
-                return Err(Error::msg(
-                    "Unicast address must be of the same family as the multicast groups.",
-                ));
-            }
-            ip
-        }
-        None => {
-            let Some(&x) = interface
-                .ips
-                .iter()
-                .find(|&ip| ip.is_ipv6() == args.discovery_socket.is_ipv6())
-            else {
-                return Err(Error::msg(
-                    "Cannot find any suitable unicast address on the selected interface.",
-                ));
-            };
-            x
-        }
-    };
-
-    Ok(ServerState {
-        token: CancellationToken::new(),
-        unicast,
-        interface_id,
-        interface,
-        metadata_socket: SetOnce::new(),
-        request_socket: SetOnce::new(),
-        image: image::compute_image_metadata(&args.file, args.chunk_size)?,
-        args,
-    })
+    Ok(SigningKey::from_bytes(&key))
 }
 
 #[tokio::main]
@@ -130,13 +127,62 @@ async fn main() -> Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
     let args = ServerArgs::parse();
-    let state = Arc::new(args_to_state(args)?);
 
-    let discovery_task = tasks::spawn(tasks::server_discovery(state.clone()));
-    let metadata_task = tasks::spawn(tasks::metadata_server(state.clone()));
-    let transfer_task = tasks::spawn(tasks::chunk_request_server(state.clone()));
+    let builder = if args.file.as_os_str() == "-" {
+        Server::stream_builder(io::stdin())
+    } else if args.stream {
+        Server::stream_builder(fs::File::open(&args.file)?)
+    } else {
+        Server::builder(args.file)
+    };
 
-    try_join!(discovery_task, metadata_task, transfer_task)?;
+    let hash = args.hash.unwrap_or(if args.signing_key.is_some() {
+        HashAlgorithm::Blake3
+    } else {
+        HashAlgorithm::default()
+    });
+
+    let server = builder
+        .discovery_socket(args.discovery_socket)
+        .transfer_socket(args.transfer_socket)
+        .metadata_group((!args.no_metadata_carousel).then_some(args.metadata_group))
+        .request_group(args.request_group)
+        .metadata_rate(args.metadata_rate)
+        .tcp_metadata(!args.no_tcp_metadata)
+        .unicast_address(args.unicast_address)
+        .interface(args.interface)
+        .max_hops(args.max_hops)
+        .discovery_interval(Duration::from_millis(args.discovery_interval))
+        .chunk_size(args.chunk_size)
+        .content_defined_chunking(args.content_defined_chunking)
+        .max_udp_payload_size(args.max_udp_payload_size)
+        .flood_speed(args.flood_speed)
+        .min_flood_speed(args.min_flood_speed)
+        .target_loss(args.target_loss)
+        .drop_slow_after(args.drop_slow_after.map(Duration::from_secs))
+        .expect_clients(args.expect_clients)
+        .idle_timeout(args.idle_timeout.map(Duration::from_secs))
+        .fec_overhead(args.fec_overhead)
+        .compression_level(args.compression_level)
+        .metadata_file(args.metadata_file)
+        .metadata_cache(!args.no_metadata_cache)
+        .hash_threads(args.hash_threads)
+        .direct_io(args.direct_io)
+        .stream_window(args.stream_window)
+        .carousel(args.carousel)
+        .scheduling(args.scheduling)
+        .signing_key(
+            args.signing_key
+                .as_deref()
+                .map(load_signing_key)
+                .transpose()?,
+        )
+        .hash(hash)
+        .build()?;
+
+    if args.metadata_only {
+        return Ok(());
+    }
 
-    Ok(())
+    server.run().await
 }
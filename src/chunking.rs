@@ -0,0 +1,205 @@
+use std::{
+    io::{ErrorKind, Read, Result},
+    ops::ControlFlow,
+};
+
+use fastcdc::v2020::{self, StreamCDC};
+use serde::{Deserialize, Serialize};
+
+/// Size of a memory page, to which read buffers are aligned.
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 { size as usize } else { 4096 }
+}
+
+#[cfg(not(unix))]
+pub fn page_size() -> usize {
+    4096
+}
+
+/// How the files of an image are cut into chunks.
+///
+/// Clients cut their seed files the same way to find chunks they already have.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Chunking {
+    /// Chunks of `size` bytes, except for the last one of each file.
+    Fixed { size: usize },
+    /// FastCDC chunks, whose boundaries depend on the content. Inserting or
+    /// removing data only changes the chunks around the edit, so that shifted
+    /// content still yields the same chunks.
+    ContentDefined { min: u32, avg: u32, max: u32 },
+}
+
+impl Chunking {
+    /// Content-defined chunking producing chunks of at most `max_size` bytes.
+    pub fn content_defined(max_size: usize) -> Chunking {
+        let max = (max_size as u32).clamp(v2020::MAXIMUM_MIN, v2020::MAXIMUM_MAX);
+        let avg = (max / 4).clamp(v2020::AVERAGE_MIN, v2020::AVERAGE_MAX);
+        let min = (avg / 4).clamp(v2020::MINIMUM_MIN, v2020::MINIMUM_MAX);
+        Chunking::ContentDefined { min, avg, max }
+    }
+
+    pub fn max_size(&self) -> usize {
+        match *self {
+            Chunking::Fixed { size } => size,
+            Chunking::ContentDefined { max, .. } => max as usize,
+        }
+    }
+
+    /// Cuts `source` into chunks, calling `f` with the offset and content of
+    /// each of them in order until it breaks.
+    pub fn split(
+        &self,
+        mut source: impl Read,
+        mut f: impl FnMut(u64, &[u8]) -> Result<ControlFlow<()>>,
+    ) -> Result<()> {
+        match *self {
+            Chunking::Fixed { size } => {
+                // Align the read buffer to page boundary.
+                // It can speed up read() of 2x, and direct reads require it.
+                let align = page_size();
+                let mut buf = vec![0u8; size + align].into_boxed_slice();
+                let ptr = buf.as_ptr() as usize;
+                let offset = (align - (ptr % align)) % align;
+                let buf = &mut buf[offset..offset + size];
+
+                let mut pos = 0u64;
+                loop {
+                    let mut len = 0;
+                    while len < size {
+                        match source.read(&mut buf[len..]) {
+                            Ok(0) => break,
+                            Ok(n) => len += n,
+                            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    if len == 0 {
+                        return Ok(());
+                    }
+                    if f(pos, &buf[0..len])?.is_break() {
+                        return Ok(());
+                    }
+                    pos += len as u64;
+                }
+            }
+            Chunking::ContentDefined { min, avg, max } => {
+                for chunk in StreamCDC::new(source, min, avg, max) {
+                    let chunk = chunk?;
+                    if f(chunk.offset, &chunk.data)?.is_break() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that do not repeat, for content-defined boundaries to be found.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn split(chunking: Chunking, data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        chunking
+            .split(data, |offset, chunk| {
+                chunks.push((offset, chunk.to_vec()));
+                Ok(ControlFlow::Continue(()))
+            })
+            .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn fixed_chunks() {
+        let data = noise(10_000, 1);
+        let chunks = split(Chunking::Fixed { size: 4096 }, &data);
+        let offsets: Vec<u64> = chunks.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, vec![0, 4096, 8192]);
+        assert_eq!(chunks[2].1.len(), 10_000 - 8192);
+        assert_eq!(chunks.concat_data(), data);
+
+        assert!(split(Chunking::Fixed { size: 4096 }, &[]).is_empty());
+        assert_eq!(
+            split(Chunking::Fixed { size: 4096 }, &data[0..4096]).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn split_stops_on_break() {
+        let data = noise(10_000, 2);
+        let mut calls = 0;
+        Chunking::Fixed { size: 1000 }
+            .split(&data[..], |_, _| {
+                calls += 1;
+                Ok(if calls == 3 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                })
+            })
+            .unwrap();
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn content_defined_chunks() {
+        let chunking = Chunking::content_defined(64 * 1024);
+        let Chunking::ContentDefined { min, max, .. } = chunking else {
+            unreachable!();
+        };
+        assert_eq!(chunking.max_size(), 64 * 1024);
+
+        let data = noise(1024 * 1024, 3);
+        let chunks = split(chunking, &data);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat_data(), data);
+        let mut offset = 0;
+        for (i, (chunk_offset, chunk)) in chunks.iter().enumerate() {
+            assert_eq!(*chunk_offset, offset);
+            assert!(chunk.len() <= max as usize);
+            assert!(chunk.len() >= min as usize || i == chunks.len() - 1);
+            offset += chunk.len() as u64;
+        }
+    }
+
+    #[test]
+    fn content_defined_chunks_survive_shifts() {
+        let chunking = Chunking::content_defined(64 * 1024);
+        let data = noise(1024 * 1024, 4);
+        let mut shifted = noise(1000, 5);
+        shifted.extend_from_slice(&data);
+
+        let original: Vec<Vec<u8>> = split(chunking, &data).into_iter().map(|x| x.1).collect();
+        let moved: Vec<Vec<u8>> = split(chunking, &shifted).into_iter().map(|x| x.1).collect();
+        let shared = original.iter().filter(|x| moved.contains(x)).count();
+        assert!(shared >= original.len() - 2);
+    }
+
+    trait ConcatData {
+        fn concat_data(&self) -> Vec<u8>;
+    }
+
+    impl ConcatData for Vec<(u64, Vec<u8>)> {
+        fn concat_data(&self) -> Vec<u8> {
+            self.iter().flat_map(|(_, x)| x.iter().copied()).collect()
+        }
+    }
+}
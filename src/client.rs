@@ -0,0 +1,371 @@
+mod chunk;
+mod device;
+mod output;
+mod request;
+mod seed;
+mod sparse;
+mod stream;
+mod tasks;
+mod tree;
+
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::{Error, Result};
+use ed25519_dalek::VerifyingKey;
+use serde::Serialize;
+use socket2::InterfaceIndexOrAddress;
+use tokio::{
+    io::AsyncWrite,
+    sync::{SetOnce, watch},
+    try_join,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    ClientId, ImageMetadata, ServerDiscovery,
+    net::{NetworkInterface, get_interface},
+};
+
+/// Options of a [`Client`], see [`ClientBuilder`] for their meaning.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub discovery_socket: SocketAddr,
+    pub force: bool,
+    pub sparse: bool,
+    pub seeds: Vec<PathBuf>,
+    pub tcp_metadata: bool,
+    pub interface: Option<String>,
+    pub unicast_address: Option<IpAddr>,
+    pub hops: u32,
+    pub trusted_keys: Vec<VerifyingKey>,
+    pub reorder_buffer: u64,
+    pub passive: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            discovery_socket: SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0xff18, 0, 0, 0, 0, 0, 0, 1),
+                7890,
+                0,
+                0,
+            )),
+            force: false,
+            sparse: false,
+            seeds: Vec::new(),
+            tcp_metadata: false,
+            interface: None,
+            unicast_address: None,
+            hops: 1,
+            trusted_keys: Vec::new(),
+            reorder_buffer: 64 * 1024 * 1024,
+            passive: false,
+        }
+    }
+}
+
+/// What a [`Client`] is currently doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientPhase {
+    #[default]
+    Discovering,
+    FetchingMetadata,
+    Receiving,
+    Verifying,
+    Done,
+    /// The transfer stopped on an error.
+    Failed,
+}
+
+/// Snapshot of the state of a running [`Client`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ClientProgress {
+    pub phase: ClientPhase,
+    /// Request socket of the server, once discovered.
+    pub server: Option<SocketAddr>,
+    /// Size of the image, known once its metadata has been retrieved.
+    pub bytes_total: u64,
+    /// Bytes of the image already present in the target.
+    pub bytes_written: u64,
+    pub chunks_total: usize,
+    pub chunks_complete: usize,
+    /// Rate at which data was written to the target over the last second, in
+    /// bits per second.
+    pub rate: u64,
+    /// Chunk data received from the server, as sent on the wire.
+    pub bytes_received: u64,
+    /// Hash algorithm and hex encoded digest of the image once verified, such
+    /// as `xxh3:20238e19fda87100`.
+    pub digest: Option<String>,
+    /// Chunks that were discarded because their hash did not match.
+    pub corrupted_chunks: u64,
+    /// Retransmission requests sent to the server.
+    pub requests_sent: u64,
+}
+
+struct ClientState {
+    token: CancellationToken,
+    id: ClientId,
+    interface: NetworkInterface,
+    interface_id: InterfaceIndexOrAddress,
+    unicast: IpAddr,
+    /// Unused when writing to an output stream.
+    target: PathBuf,
+    config: ClientConfig,
+    progress: watch::Sender<ClientProgress>,
+    server: SetOnce<ServerDiscovery>,
+    image: SetOnce<ImageMetadata>,
+}
+
+/// Where the image is written.
+enum Target {
+    Path(PathBuf),
+    Output(Box<dyn AsyncWrite + Send + Unpin>),
+}
+
+pub struct ClientBuilder {
+    target: Target,
+    config: ClientConfig,
+}
+
+impl ClientBuilder {
+    pub fn new(target: impl Into<PathBuf>) -> ClientBuilder {
+        ClientBuilder {
+            target: Target::Path(target.into()),
+            config: ClientConfig::default(),
+        }
+    }
+
+    /// Writes the image to `output` in order instead of to a path, see
+    /// [`Client::output_builder`].
+    pub fn output(output: impl AsyncWrite + Send + Unpin + 'static) -> ClientBuilder {
+        ClientBuilder {
+            target: Target::Output(Box::new(output)),
+            config: ClientConfig::default(),
+        }
+    }
+
+    /// Replaces all the options at once.
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Multicast group on which servers announce themselves.
+    pub fn discovery_socket(mut self, discovery_socket: SocketAddr) -> Self {
+        self.config.discovery_socket = discovery_socket;
+        self
+    }
+
+    /// Download every chunk, even those already present in the target.
+    pub fn force(mut self, force: bool) -> Self {
+        self.config.force = force;
+        self
+    }
+
+    /// Deallocate the ranges of regular files that only contain zeros instead
+    /// of writing them.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.config.sparse = sparse;
+        self
+    }
+
+    /// Files or devices that likely share content with the image, such as an
+    /// older version of it. Chunks found in them are not downloaded.
+    pub fn seeds(mut self, seeds: Vec<PathBuf>) -> Self {
+        self.config.seeds = seeds;
+        self
+    }
+
+    /// Retrieve the image metadata over TCP when the server offers it,
+    /// instead of waiting for it on its multicast group.
+    pub fn tcp_metadata(mut self, tcp_metadata: bool) -> Self {
+        self.config.tcp_metadata = tcp_metadata;
+        self
+    }
+
+    /// Name or index of the network interface, defaults to the first multicast
+    /// capable one.
+    pub fn interface(mut self, interface: Option<String>) -> Self {
+        self.config.interface = interface;
+        self
+    }
+
+    /// Address from which requests are sent, defaults to one of the addresses
+    /// of the interface.
+    pub fn unicast_address(mut self, unicast_address: Option<IpAddr>) -> Self {
+        self.config.unicast_address = unicast_address;
+        self
+    }
+
+    pub fn hops(mut self, hops: u32) -> Self {
+        self.config.hops = hops;
+        self
+    }
+
+    /// Only accept servers signing with one of these keys. Any server is
+    /// accepted when empty.
+    pub fn trusted_keys(mut self, trusted_keys: Vec<VerifyingKey>) -> Self {
+        self.config.trusted_keys = trusted_keys;
+        self
+    }
+
+    /// Bytes of the image past the first missing chunk that are received
+    /// ahead of time when writing to an output stream. Chunks further away
+    /// are left for later.
+    pub fn reorder_buffer(mut self, reorder_buffer: u64) -> Self {
+        self.config.reorder_buffer = reorder_buffer;
+        self
+    }
+
+    /// Never send anything to the server, for one-way links. Lost chunks are
+    /// only received when the server sends them again anyway, as it does in
+    /// carousel mode, and the image metadata must be carouselled.
+    pub fn passive(mut self, passive: bool) -> Self {
+        self.config.passive = passive;
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let config = self.config;
+
+        let (target, output) = match self.target {
+            Target::Path(path) => (path, None),
+            Target::Output(output) => {
+                if !config.seeds.is_empty() {
+                    return Err(Error::msg(
+                        "Seeds cannot be used when writing to an output stream.",
+                    ));
+                }
+                if config.reorder_buffer == 0 {
+                    return Err(Error::msg("Reorder buffer cannot be empty."));
+                }
+                (PathBuf::new(), Some(output))
+            }
+        };
+
+        if !config.discovery_socket.ip().is_multicast() {
+            return Err(Error::msg("Discovery address must be a multicast group."));
+        }
+
+        let Some(interface) = get_interface(config.interface.as_deref())? else {
+            return Err(Error::msg("Cannot find requested interface."));
+        };
+
+        let interface_id = if config.discovery_socket.is_ipv6() {
+            InterfaceIndexOrAddress::Index(interface.index)
+        } else {
+            let address = interface
+                .ips
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip),
+                    _ => None,
+                })
+                .next();
+            if let Some(&address) = address {
+                InterfaceIndexOrAddress::Address(address)
+            } else {
+                return Err(Error::msg(
+                    "In IPv4 mode the selected interface needs to have at least one IPv4 address assigned to it.",
+                ));
+            }
+        };
+
+        let unicast = match config.unicast_address {
+            Some(ip) => {
+                if ip.is_ipv6() != config.discovery_socket.is_ipv6() {
+                    return Err(Error::msg(
+                        "Unicast address must be of the same family as the multicast groups.",
+                    ));
+                }
+                ip
+            }
+            None => {
+                let Some(&x) = interface
+                    .ips
+                    .iter()
+                    .find(|&ip| ip.is_ipv6() == config.discovery_socket.is_ipv6())
+                else {
+                    return Err(Error::msg(
+                        "Cannot find any suitable unicast address on the selected interface.",
+                    ));
+                };
+                x
+            }
+        };
+
+        Ok(Client {
+            state: Arc::new(ClientState {
+                token: CancellationToken::new(),
+                id: getrandom::u64()?,
+                unicast,
+                interface_id,
+                interface,
+                target,
+                config,
+                progress: watch::Sender::new(ClientProgress::default()),
+                server: SetOnce::new(),
+                image: SetOnce::new(),
+            }),
+            output,
+        })
+    }
+}
+
+/// Receives an image from the first server discovered on the network and
+/// writes it to a target path or an output stream.
+pub struct Client {
+    state: Arc<ClientState>,
+    /// The output stream to write to, taken when running.
+    output: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+}
+
+impl Client {
+    pub fn builder(target: impl Into<PathBuf>) -> ClientBuilder {
+        ClientBuilder::new(target)
+    }
+
+    /// Writes the image to `output`, such as standard output, in order. Only
+    /// images made of a single file can be written this way.
+    pub fn output_builder(output: impl AsyncWrite + Send + Unpin + 'static) -> ClientBuilder {
+        ClientBuilder::output(output)
+    }
+
+    /// Token that stops the client when cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.state.token.clone()
+    }
+
+    pub fn progress(&self) -> watch::Receiver<ClientProgress> {
+        self.state.progress.subscribe()
+    }
+
+    /// Receives the whole image and verifies it.
+    pub async fn run(self) -> Result<()> {
+        let state = self.state;
+
+        let server_discovery = tasks::spawn(tasks::server_discovery(state.clone()));
+        let metadata_transfer = tasks::spawn(tasks::metadata_transfer(state.clone()));
+        let chunk_transfer = tasks::spawn(tasks::chunk_transfer(state.clone(), self.output));
+
+        let result = try_join!(server_discovery, metadata_transfer, chunk_transfer);
+
+        // The other tasks would keep waiting for what the failed one was to
+        // provide, holding on to the state and its progress sender.
+        if result.is_err() {
+            state
+                .progress
+                .send_modify(|progress| progress.phase = ClientPhase::Failed);
+            state.token.cancel();
+        }
+
+        result.map(|_| ())
+    }
+}
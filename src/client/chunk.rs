@@ -0,0 +1,282 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Result, bail};
+use raptorq::{Decoder, EncodingPacket, ObjectTransmissionInformation, PayloadId, partition};
+
+use crate::FecParameters;
+
+pub enum Assembler {
+    Plain(ChunkAssembler),
+    Fec(FecAssembler),
+}
+
+impl Assembler {
+    pub fn new(chunk_size: usize, fec: Option<&FecParameters>) -> Assembler {
+        match fec {
+            Some(fec) => Assembler::Fec(FecAssembler::new(fec.transmission_info(chunk_size))),
+            None => Assembler::Plain(ChunkAssembler::new(chunk_size)),
+        }
+    }
+
+    pub fn add_fragment(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        match self {
+            Assembler::Plain(x) => x.add_fragment(offset, data),
+            Assembler::Fec(x) => x.add_fragment(offset, data),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match self {
+            Assembler::Plain(x) => x.is_complete(),
+            Assembler::Fec(x) => x.is_complete(),
+        }
+    }
+
+    /// Ranges of the chunk still needed to complete it, as `(offset, len)` pairs.
+    pub fn missing_ranges(&self) -> Vec<(usize, usize)> {
+        match self {
+            Assembler::Plain(x) => x.missing_ranges(),
+            Assembler::Fec(x) => vec![(0, x.missing_bytes())],
+        }
+    }
+
+    pub fn complete(self) -> Vec<u8> {
+        match self {
+            Assembler::Plain(x) => x.complete(),
+            Assembler::Fec(x) => x.complete(),
+        }
+    }
+}
+
+pub struct ChunkAssembler {
+    data: Vec<u8>,
+    map: BTreeSet<(usize, usize)>,
+}
+
+impl ChunkAssembler {
+    pub fn new(chunk_size: usize) -> ChunkAssembler {
+        ChunkAssembler {
+            data: vec![0u8; chunk_size],
+            map: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_fragment(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let before = self
+            .map
+            .range(..(offset, 0))
+            .next_back()
+            .copied()
+            .unwrap_or((0, 0));
+        let after = self
+            .map
+            .range((offset, 0)..)
+            .next()
+            .copied()
+            .unwrap_or((self.data.len(), 0));
+
+        let mut add = (offset, data.len());
+
+        if before.0 + before.1 > add.0 || add.0 + add.1 > after.0 {
+            bail!("Fragment overlaps with data or is outside of chunk boundaries");
+        }
+
+        let _ = &self.data[add.0..add.0 + add.1].copy_from_slice(data);
+
+        if before.0 + before.1 == add.0 {
+            self.map.remove(&before);
+            add.0 = before.0;
+            add.1 += before.1;
+        }
+
+        if add.0 + add.1 == after.0 {
+            self.map.remove(&after);
+            add.1 += after.1;
+        }
+
+        self.map.insert(add);
+
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.map.len() == 1 && *self.map.first().unwrap() == (0, self.data.len())
+    }
+
+    pub fn missing_ranges(&self) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+        for &(offset, len) in self.map.iter() {
+            if offset > pos {
+                out.push((pos, offset - pos));
+            }
+            pos = offset + len;
+        }
+        if pos < self.data.len() {
+            out.push((pos, self.data.len() - pos));
+        }
+        out
+    }
+
+    pub fn complete(self) -> Vec<u8> {
+        if !self.is_complete() {
+            panic!("Tried to complete an incomplete chunk");
+        }
+
+        self.data
+    }
+}
+
+pub struct FecAssembler {
+    config: ObjectTransmissionInformation,
+    decoder: Decoder,
+    /// Source symbols of each source block and distinct encoding symbols
+    /// received for it.
+    blocks: Vec<(u32, u32)>,
+    /// Payload identifiers of the encoding symbols received, which the server
+    /// may send more than once.
+    received: BTreeSet<u32>,
+    data: Option<Vec<u8>>,
+}
+
+impl FecAssembler {
+    pub fn new(config: ObjectTransmissionInformation) -> FecAssembler {
+        let symbols = config
+            .transfer_length()
+            .div_ceil(config.symbol_size() as u64) as u32;
+        let (kl, ks, zl, zs) = partition(symbols, config.source_blocks());
+        let blocks = (0..zl)
+            .map(|_| (kl, 0))
+            .chain((0..zs).map(|_| (ks, 0)))
+            .collect();
+
+        FecAssembler {
+            config,
+            decoder: Decoder::new(config),
+            blocks,
+            received: BTreeSet::new(),
+            data: None,
+        }
+    }
+
+    /// Estimate of the encoding symbols (in bytes) still needed to decode the chunk.
+    pub fn missing_bytes(&self) -> usize {
+        if self.is_complete() {
+            return 0;
+        }
+
+        // Decoding can fail with exactly as many symbols as the source block has,
+        // so always ask for at least one more.
+        let symbols: u32 = self
+            .blocks
+            .iter()
+            .map(|&(needed, received)| needed.saturating_sub(received))
+            .sum();
+        symbols.max(1) as usize * self.config.symbol_size() as usize
+    }
+
+    pub fn add_fragment(&mut self, payload_id: usize, data: &[u8]) -> Result<()> {
+        let Ok(payload_id) = u32::try_from(payload_id) else {
+            bail!("Invalid payload identifier");
+        };
+        let serialized = payload_id;
+        let payload_id = PayloadId::deserialize(&payload_id.to_be_bytes());
+
+        if payload_id.source_block_number() >= self.config.source_blocks()
+            || data.len() != self.config.symbol_size() as usize
+        {
+            bail!("Encoding symbol does not match the transmission parameters");
+        }
+
+        if self.data.is_none() && self.received.insert(serialized) {
+            self.blocks[payload_id.source_block_number() as usize].1 += 1;
+            self.data = self
+                .decoder
+                .decode(EncodingPacket::new(payload_id, data.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.data.is_some()
+    }
+
+    pub fn complete(self) -> Vec<u8> {
+        self.data.expect("Tried to complete an incomplete chunk")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fec_fragment(block: u8, symbol: u32) -> usize {
+        u32::from_be_bytes(PayloadId::new(block, symbol).serialize()) as usize
+    }
+
+    #[test]
+    fn plain_missing_ranges() {
+        let mut assembler = ChunkAssembler::new(100);
+        assert_eq!(assembler.missing_ranges(), vec![(0, 100)]);
+        assembler.add_fragment(10, &[1; 20]).unwrap();
+        assembler.add_fragment(50, &[2; 10]).unwrap();
+        assert_eq!(
+            assembler.missing_ranges(),
+            vec![(0, 10), (30, 20), (60, 40)]
+        );
+        assert!(assembler.add_fragment(25, &[3; 10]).is_err());
+        assert!(assembler.add_fragment(95, &[3; 10]).is_err());
+        assembler.add_fragment(0, &[4; 10]).unwrap();
+        assembler.add_fragment(30, &[5; 20]).unwrap();
+        assembler.add_fragment(60, &[6; 40]).unwrap();
+        assert!(assembler.is_complete());
+        assert!(assembler.missing_ranges().is_empty());
+    }
+
+    #[test]
+    fn fec_duplicates_are_not_progress() {
+        let fec = FecParameters {
+            symbol_size: 128,
+            overhead: 10,
+        };
+        let mut assembler = FecAssembler::new(fec.transmission_info(1280));
+        assert_eq!(assembler.missing_bytes(), 1280);
+
+        assembler
+            .add_fragment(fec_fragment(0, 20), &[0; 128])
+            .unwrap();
+        assert_eq!(assembler.missing_bytes(), 1152);
+        assembler
+            .add_fragment(fec_fragment(0, 20), &[0; 128])
+            .unwrap();
+        assembler
+            .add_fragment(fec_fragment(0, 20), &[0; 128])
+            .unwrap();
+        assert_eq!(assembler.missing_bytes(), 1152);
+        assembler
+            .add_fragment(fec_fragment(0, 21), &[0; 128])
+            .unwrap();
+        assert_eq!(assembler.missing_bytes(), 1024);
+    }
+
+    #[test]
+    fn fec_rejects_mismatched_symbols() {
+        let fec = FecParameters {
+            symbol_size: 128,
+            overhead: 10,
+        };
+        let mut assembler = FecAssembler::new(fec.transmission_info(1280));
+        assert!(
+            assembler
+                .add_fragment(fec_fragment(1, 0), &[0; 128])
+                .is_err()
+        );
+        assert!(
+            assembler
+                .add_fragment(fec_fragment(0, 0), &[0; 64])
+                .is_err()
+        );
+        assert_eq!(assembler.missing_bytes(), 1280);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    fs::{self, Metadata},
+    io::{self, Result},
+    path::Path,
+    sync::Arc,
+};
+
+use tokio::{fs::File, task::spawn_blocking};
+
+/// Alignment of the offsets, lengths and buffers of direct writes. Any
+/// logical block size up to a page is a divisor of it.
+const DIRECT_ALIGN: usize = 4096;
+
+#[cfg(unix)]
+pub fn is_block_device(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+pub fn is_block_device(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Size of a block device, which its metadata reports as zero.
+#[cfg(target_os = "linux")]
+pub async fn device_size(file: &File) -> Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    const BLKGETSIZE64: libc::Ioctl = libc::_IOR::<u64>(0x12, 114);
+
+    let mut size = 0u64;
+    // SAFETY: BLKGETSIZE64 writes a single u64 to the pointer.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size as *mut u64) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn device_size(file: &File) -> Result<u64> {
+    use tokio::io::AsyncSeekExt;
+    file.try_clone().await?.seek(io::SeekFrom::End(0)).await
+}
+
+/// Size of a regular file or block device.
+pub async fn file_size(file: &File) -> Result<u64> {
+    let metadata = file.metadata().await?;
+    if is_block_device(&metadata) {
+        device_size(file).await
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+/// Zeroes a range of a block device, letting the device do it when it can.
+#[cfg(target_os = "linux")]
+pub fn zero_out(file: &File, offset: u64, len: usize) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    const BLKZEROOUT: libc::Ioctl = libc::_IO(0x12, 127);
+
+    let range = [offset, len as u64];
+    // SAFETY: BLKZEROOUT reads two u64 from the pointer.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKZEROOUT, range.as_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn zero_out(_file: &File, _offset: u64, _len: usize) -> Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Writes to a block device bypassing the page cache, which would otherwise
+/// fill up with the whole image.
+pub struct DirectWriter {
+    file: Arc<fs::File>,
+    buf: Vec<u8>,
+}
+
+impl DirectWriter {
+    #[cfg(target_os = "linux")]
+    pub fn open(path: &Path) -> Result<DirectWriter> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = fs::File::options()
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Ok(DirectWriter {
+            file: Arc::new(file),
+            buf: Vec::new(),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_path: &Path) -> Result<DirectWriter> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Writes `data` at `offset`, unless they are not aligned for direct
+    /// writes, in which case nothing is done and `false` is returned.
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<bool> {
+        if !offset.is_multiple_of(DIRECT_ALIGN as u64) || !data.len().is_multiple_of(DIRECT_ALIGN) {
+            return Ok(false);
+        }
+
+        let len = data.len();
+        aligned_slice(&mut self.buf, len).copy_from_slice(data);
+
+        let file = self.file.clone();
+        let mut buf = std::mem::take(&mut self.buf);
+        self.buf = spawn_blocking(move || {
+            write_all_at(&file, aligned_slice(&mut buf, len), offset)?;
+            Ok::<_, io::Error>(buf)
+        })
+        .await
+        .map_err(io::Error::other)??;
+
+        Ok(true)
+    }
+}
+
+/// Returns a slice of `len` bytes of `buf` starting on an aligned address,
+/// growing `buf` as needed.
+fn aligned_slice(buf: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    if buf.len() < len + DIRECT_ALIGN {
+        buf.resize(len + DIRECT_ALIGN, 0);
+    }
+    let offset = (DIRECT_ALIGN - (buf.as_ptr() as usize % DIRECT_ALIGN)) % DIRECT_ALIGN;
+    &mut buf[offset..offset + len]
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &fs::File, data: &[u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(_file: &fs::File, _data: &[u8], _offset: u64) -> Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
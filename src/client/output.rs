@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::hash::{Digest, HashAlgorithm, Hasher};
+
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// A piece of an image made of a single file, for the writers.
+pub enum StreamBlock {
+    Data { offset: u64, data: Vec<u8> },
+    Zeros { offset: u64, len: usize },
+}
+
+impl StreamBlock {
+    fn offset(&self) -> u64 {
+        match self {
+            StreamBlock::Data { offset, .. } | StreamBlock::Zeros { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Writes an image made of a single file to a stream in order, holding on to
+/// the pieces that arrive early. What is written is hashed on the way, since
+/// it cannot be read back to be verified.
+pub struct OrderedOutput {
+    output: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Offset of the next piece to write.
+    position: u64,
+    pending: BTreeMap<u64, StreamBlock>,
+    hasher: Hasher,
+}
+
+impl OrderedOutput {
+    pub fn new(output: Box<dyn AsyncWrite + Send + Unpin>, hash: HashAlgorithm) -> OrderedOutput {
+        OrderedOutput {
+            output,
+            position: 0,
+            pending: BTreeMap::new(),
+            hasher: hash.hasher(),
+        }
+    }
+
+    /// Offset of the next piece to write, everything before it was written.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Writes `block` if it comes next, followed by any held piece it makes
+    /// contiguous. Returns the amount of bytes written.
+    pub async fn write(&mut self, block: StreamBlock) -> Result<u64> {
+        let start = self.position;
+        self.pending.insert(block.offset(), block);
+
+        while let Some(entry) = self.pending.first_entry()
+            && *entry.key() == self.position
+        {
+            match entry.remove() {
+                StreamBlock::Data { data, .. } => {
+                    self.hasher.update(&data);
+                    self.output.write_all(&data).await?;
+                    self.position += data.len() as u64;
+                }
+                StreamBlock::Zeros { len, .. } => {
+                    let mut left = len;
+                    while left > 0 {
+                        let n = left.min(ZEROS.len());
+                        self.hasher.update(&ZEROS[0..n]);
+                        self.output.write_all(&ZEROS[0..n]).await?;
+                        left -= n;
+                    }
+                    self.position += len as u64;
+                }
+            }
+        }
+
+        Ok(self.position - start)
+    }
+
+    /// Flushes the output once all of its `size` bytes were written, returns
+    /// the digest of what was written.
+    pub async fn finish(mut self, size: u64) -> Result<Digest> {
+        if self.position != size {
+            bail!(
+                "Output stream stopped at offset {} of {} bytes",
+                self.position,
+                size
+            );
+        }
+        self.output.flush().await?;
+        Ok(self.hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> OrderedOutput {
+        OrderedOutput::new(Box::new(tokio::io::sink()), HashAlgorithm::Blake3)
+    }
+
+    fn data(offset: u64, data: &[u8]) -> StreamBlock {
+        StreamBlock::Data {
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_in_order() {
+        let mut output = output();
+        assert_eq!(output.write(data(3, b"def")).await.unwrap(), 0);
+        assert_eq!(output.write(data(8, b"ij")).await.unwrap(), 0);
+        assert_eq!(output.position(), 0);
+        assert_eq!(output.write(data(0, b"abc")).await.unwrap(), 6);
+        assert_eq!(output.position(), 6);
+        assert_eq!(output.write(data(6, b"gh")).await.unwrap(), 4);
+        assert_eq!(
+            output.finish(10).await.unwrap(),
+            HashAlgorithm::Blake3.hash(b"abcdefghij")
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_zeros() {
+        let mut output = output();
+        let len = ZEROS.len() * 2 + 10;
+        output.write(data(len as u64, b"end")).await.unwrap();
+        let written = output
+            .write(StreamBlock::Zeros { offset: 0, len })
+            .await
+            .unwrap();
+        assert_eq!(written, len as u64 + 3);
+
+        let mut expected = vec![0u8; len];
+        expected.extend_from_slice(b"end");
+        assert_eq!(
+            output.finish(expected.len() as u64).await.unwrap(),
+            HashAlgorithm::Blake3.hash(&expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn finish_needs_every_piece() {
+        let mut output = output();
+        output.write(data(0, b"abc")).await.unwrap();
+        output.write(data(6, b"ghi")).await.unwrap();
+        assert!(output.finish(9).await.is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, future::pending, time::Duration};
+
+use anyhow::Result;
+use tokio::{net::UdpSocket, time::Instant};
+
+use super::{ClientState, tasks::unicast_bind_address};
+use crate::{
+    Capacity, ClientId, ClientMessage, ClientPacket, FragmentRange, FragmentRanges,
+    ReceptionReport, ServerDiscovery, SessionId,
+    net::{new_receiver_multicast_socket, new_sender_multicast_socket},
+};
+
+/// Time between requests for missing chunks. In a stream, also the time
+/// without data after which all of them are requested.
+pub const REQUEST_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest random delay added to the time between requests when they are
+/// sent to the request group, so that clients missing the same chunks do not
+/// all ask at once and the first one to ask silences the others.
+const REQUEST_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Time during which a range another client asked for is not asked for
+/// again, leaving the server time to send it. The other client keeps asking
+/// while it misses the range, so this only needs to cover a couple of its
+/// requests.
+const HEARD_HOLD: Duration = Duration::from_millis(200);
+
+/// A range of a chunk some client asked for lately.
+struct HeardRange {
+    offset: usize,
+    end: usize,
+    at: Instant,
+}
+
+/// Sends the packets of a client to the server.
+///
+/// When the server has a request group, requests go to the group instead of
+/// the request socket. Every client hears them there and leaves out of its
+/// own requests the ranges another client just asked for, as the server
+/// sends them to everyone anyway.
+pub struct Requester {
+    session: SessionId,
+    client: ClientId,
+    /// Connected to the request socket of the server.
+    socket: UdpSocket,
+    /// Sending and receiving sockets of the request group, if any.
+    group: Option<(UdpSocket, UdpSocket)>,
+    heard: BTreeMap<usize, Vec<HeardRange>>,
+    buf: Vec<u8>,
+    heard_buf: Vec<u8>,
+}
+
+impl Requester {
+    pub async fn new(state: &ClientState, server: &ServerDiscovery) -> Result<Requester> {
+        let socket = UdpSocket::bind(unicast_bind_address(state)).await?;
+        socket.connect(server.request_socket).await?;
+
+        let group = match server.request_group {
+            Some(group) => Some((
+                new_sender_multicast_socket(
+                    group,
+                    unicast_bind_address(state),
+                    state.interface_id,
+                    state.config.hops,
+                )
+                .await?,
+                new_receiver_multicast_socket(group, state.interface_id).await?,
+            )),
+            None => None,
+        };
+
+        Ok(Requester {
+            session: server.session,
+            client: state.id,
+            socket,
+            group,
+            heard: BTreeMap::new(),
+            buf: vec![0u8; 2500 - 40 - 8],
+            heard_buf: vec![0u8; 2500 - 40 - 8],
+        })
+    }
+
+    pub async fn report(&mut self, report: ReceptionReport) -> Result<()> {
+        let packet = ClientPacket {
+            session: self.session,
+            client: self.client,
+            message: ClientMessage::Report(report),
+        };
+        self.socket
+            .send(postcard::to_slice(&packet, &mut self.buf)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Time to wait before the next request, `interval` plus a random
+    /// backoff when requests are shared on the request group.
+    pub fn delay(&self, interval: Duration) -> Duration {
+        if self.group.is_none() {
+            return interval;
+        }
+        let backoff = REQUEST_BACKOFF.as_micros() as u64 + 1;
+        interval + Duration::from_micros(getrandom::u64().unwrap_or_default() % backoff)
+    }
+
+    /// Asks for as many of `ranges` as fit in a request, leaving out those
+    /// another client asked for lately. Returns whether a request was sent.
+    pub async fn request(
+        &mut self,
+        ranges: impl IntoIterator<Item = FragmentRange>,
+    ) -> Result<bool> {
+        self.forget(Instant::now());
+
+        let ranges: FragmentRanges = ranges
+            .into_iter()
+            .filter(|range| !self.was_heard(range))
+            .take(FragmentRanges::CAPACITY)
+            .collect();
+        if ranges.is_empty() {
+            return Ok(false);
+        }
+
+        let packet = ClientPacket {
+            session: self.session,
+            client: self.client,
+            message: ClientMessage::Request(ranges),
+        };
+        let packet = postcard::to_slice(&packet, &mut self.buf)?;
+        match &self.group {
+            Some((sender, _)) => sender.send(packet).await?,
+            None => self.socket.send(packet).await?,
+        };
+
+        Ok(true)
+    }
+
+    /// Waits for a request of another client on the request group and
+    /// records its ranges. Never completes without a request group, and is
+    /// cancel safe.
+    pub async fn hear(&mut self) -> Result<()> {
+        let Some((_, receiver)) = &self.group else {
+            return pending().await;
+        };
+        loop {
+            let size = receiver.recv(&mut self.heard_buf).await?;
+            let Ok(packet) = postcard::from_bytes::<ClientPacket>(&self.heard_buf[0..size]) else {
+                continue;
+            };
+            // Our own requests are looped back, they must not hold back the
+            // next ones.
+            if packet.session != self.session || packet.client == self.client {
+                continue;
+            }
+            if let ClientMessage::Request(ranges) = packet.message {
+                let now = Instant::now();
+                self.forget(now);
+                for range in ranges {
+                    self.record(range, now);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    fn record(&mut self, range: FragmentRange, now: Instant) {
+        self.heard.entry(range.chunk).or_default().push(HeardRange {
+            offset: range.offset,
+            end: range.offset.saturating_add(range.len),
+            at: now,
+        });
+    }
+
+    fn forget(&mut self, now: Instant) {
+        self.heard.retain(|_, ranges| {
+            ranges.retain(|range| now < range.at + HEARD_HOLD);
+            !ranges.is_empty()
+        });
+    }
+
+    fn was_heard(&self, range: &FragmentRange) -> bool {
+        self.heard.get(&range.chunk).is_some_and(|heard| {
+            heard
+                .iter()
+                .any(|x| x.offset <= range.offset && range.offset + range.len <= x.end)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn requester() -> Requester {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Requester {
+            session: SessionId {
+                session: 1,
+                image: 2,
+            },
+            client: 3,
+            socket,
+            group: None,
+            heard: BTreeMap::new(),
+            buf: Vec::new(),
+            heard_buf: Vec::new(),
+        }
+    }
+
+    fn range(chunk: usize, offset: usize, len: usize) -> FragmentRange {
+        FragmentRange { chunk, offset, len }
+    }
+
+    #[tokio::test]
+    async fn heard_ranges_cover_requests() {
+        let mut requester = requester().await;
+        let now = Instant::now();
+        requester.record(range(1, 100, 50), now);
+        requester.record(range(1, 150, 50), now);
+
+        assert!(requester.was_heard(&range(1, 100, 50)));
+        assert!(requester.was_heard(&range(1, 110, 30)));
+        assert!(requester.was_heard(&range(1, 150, 50)));
+        // Only ranges within a single heard one are left out.
+        assert!(!requester.was_heard(&range(1, 90, 20)));
+        assert!(!requester.was_heard(&range(1, 190, 20)));
+        assert!(!requester.was_heard(&range(1, 120, 60)));
+        assert!(!requester.was_heard(&range(2, 100, 50)));
+    }
+
+    #[tokio::test]
+    async fn heard_ranges_saturate() {
+        let mut requester = requester().await;
+        requester.record(range(1, usize::MAX - 10, 100), Instant::now());
+        assert!(requester.was_heard(&range(1, usize::MAX - 5, 5)));
+    }
+
+    #[tokio::test]
+    async fn heard_ranges_expire() {
+        let mut requester = requester().await;
+        let now = Instant::now();
+        requester.record(range(1, 0, 100), now);
+        requester.record(range(2, 0, 100), now + HEARD_HOLD / 2);
+
+        requester.forget(now + HEARD_HOLD / 2);
+        assert!(requester.was_heard(&range(1, 0, 100)));
+        requester.forget(now + HEARD_HOLD);
+        assert!(!requester.was_heard(&range(1, 0, 100)));
+        assert!(requester.was_heard(&range(2, 0, 100)));
+        requester.forget(now + HEARD_HOLD * 2);
+        assert!(requester.heard.is_empty());
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io,
+    ops::ControlFlow,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::{sync::mpsc::Sender, task::spawn_blocking};
+
+use super::ClientState;
+use crate::hash::Digest;
+
+/// Looks for missing chunks in the seed files, cut the same way as the files
+/// of the image, and sends the ones found to the disk writer. Returns the
+/// chunks that were found.
+pub async fn seed_chunks(
+    state: &Arc<ClientState>,
+    missing: &BTreeSet<usize>,
+    to_disk: Sender<(usize, Vec<u8>)>,
+) -> Result<BTreeSet<usize>> {
+    let image = state.image.get().unwrap();
+
+    // Zero chunks are filled in locally anyway.
+    let mut wanted: HashMap<(Digest, usize), usize> = missing
+        .iter()
+        .map(|&i| (i, &image.chunks[i]))
+        .filter(|(_, chunk)| !chunk.zero)
+        .map(|(i, chunk)| ((chunk.hash.clone(), chunk.size), i))
+        .collect();
+    let wanted_count = wanted.len();
+
+    let state = state.clone();
+    let found = spawn_blocking(move || {
+        let image = state.image.get().unwrap();
+        let mut found = BTreeSet::new();
+
+        for path in state.config.seeds.iter() {
+            if wanted.is_empty() || state.token.is_cancelled() {
+                break;
+            }
+
+            info!("Looking for missing chunks in {}", path.display());
+
+            let file = match File::open(path) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Skipping seed {} ({})", path.display(), e);
+                    continue;
+                }
+            };
+
+            let result = image.chunking.split(file, |_, data| {
+                if wanted.is_empty() || state.token.is_cancelled() {
+                    return Ok(ControlFlow::Break(()));
+                }
+                if let Some(i) = wanted.remove(&(image.hash.hash(data), data.len())) {
+                    to_disk
+                        .blocking_send((i, data.to_vec()))
+                        .map_err(io::Error::other)?;
+                    found.insert(i);
+                }
+                Ok(ControlFlow::Continue(()))
+            });
+
+            if let Err(e) = result {
+                warn!("Unable to read seed {} ({})", path.display(), e);
+            }
+        }
+
+        found
+    })
+    .await?;
+
+    info!(
+        "Found {} of {} missing chunks in the seeds",
+        found.len(),
+        wanted_count
+    );
+
+    Ok(found)
+}
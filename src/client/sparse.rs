@@ -0,0 +1,57 @@
+use std::io::Result;
+
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::device::{is_block_device, zero_out};
+
+/// Deallocates a range of a regular file, which then reads back as zeros.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, len: usize) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: fallocate only operates on the descriptor, which `file` keeps open.
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_file: &File, _offset: u64, _len: usize) -> Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Makes a range of `file` read back as zeros, deallocating it when `sparse`
+/// is set and the file system supports it, or writing zeros otherwise. Block
+/// devices are asked to zero the range themselves.
+pub async fn zero_range(file: &mut File, offset: u64, len: usize, sparse: bool) -> Result<()> {
+    if is_block_device(&file.metadata().await?) {
+        if zero_out(file, offset, len).is_ok() {
+            return Ok(());
+        }
+    } else if sparse && punch_hole(file, offset, len).is_ok() {
+        return Ok(());
+    }
+
+    let zeros = vec![0u8; len.min(1024 * 1024)];
+
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len());
+        file.write_all(&zeros[0..n]).await?;
+        left -= n;
+    }
+    Ok(())
+}
@@ -0,0 +1,582 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use log::{info, warn};
+use tokio::{
+    fs::File,
+    io::AsyncWrite,
+    select,
+    sync::{
+        SetOnce,
+        mpsc::{Receiver, Sender, channel},
+    },
+    time::{Instant, sleep_until},
+    try_join,
+};
+use zstd::bulk::decompress;
+
+use super::{
+    ClientPhase, ClientState,
+    chunk::Assembler,
+    device::{DirectWriter, is_block_device},
+    output::{OrderedOutput, StreamBlock},
+    request::{REQUEST_INTERVAL, Requester},
+    sparse::zero_range,
+    tasks::{REPORT_INTERVAL, ReceptionStats, TargetFile, notify_completion, verify_image},
+};
+use crate::{
+    ChunkData, ChunkMetadata, FecParameters, FileKind, FileMetadata, FragmentRange, ImageMetadata,
+    MAX_STREAM_WINDOW, STREAM_SIGNATURE_CONTEXT, Signed, StreamAnnouncement, StreamEnd,
+    chunking::Chunking,
+    hash::{Digest, HashAlgorithm},
+    net::new_receiver_multicast_socket,
+};
+
+/// How the chunks of the stream are cut, hashed and sent, known from the
+/// first announcement.
+struct StreamFormat {
+    chunking: Chunking,
+    hash: HashAlgorithm,
+    fec: Option<FecParameters>,
+}
+
+/// Chunks of the stream announced so far.
+#[derive(Default)]
+struct StreamChunks {
+    chunks: Vec<Option<ChunkMetadata>>,
+    /// Amount of leading chunks that are all known.
+    known: usize,
+    /// Known chunks that were not received yet.
+    missing: BTreeSet<usize>,
+    /// Chunks received or filled in.
+    complete: usize,
+    end: Option<StreamEnd>,
+}
+
+impl StreamChunks {
+    fn is_complete(&self) -> bool {
+        self.end
+            .as_ref()
+            .is_some_and(|end| self.known == end.chunks && self.complete == end.chunks)
+    }
+
+    /// Records what `announcement` adds, handing chunks of zeros over to the writer.
+    async fn update(
+        &mut self,
+        announcement: StreamAnnouncement,
+        to_disk: &Sender<StreamBlock>,
+    ) -> Result<()> {
+        // Announcements may not be signed, they must not make us allocate
+        // more than a window past what we know.
+        let end = announcement.first.checked_add(announcement.chunks.len());
+        if end.is_none_or(|end| end > announcement.window_start.saturating_add(MAX_STREAM_WINDOW))
+            || announcement.window_start > self.known.saturating_add(MAX_STREAM_WINDOW)
+        {
+            bail!("Invalid stream announcement (chunks beyond the stream window)");
+        }
+
+        for (i, chunk) in announcement.chunks.into_iter().enumerate() {
+            let index = announcement.first + i;
+            if self.chunks.get(index).is_some_and(Option::is_some) {
+                continue;
+            }
+            if self.chunks.len() <= index {
+                self.chunks.resize(index + 1, None);
+            }
+            let &[location] = chunk.locations.as_slice() else {
+                bail!(
+                    "Invalid stream announcement (chunk {} is not in one place)",
+                    index
+                );
+            };
+            if chunk.zero {
+                to_disk
+                    .send(StreamBlock::Zeros {
+                        offset: location.offset,
+                        len: chunk.size,
+                    })
+                    .await?;
+                self.complete += 1;
+            } else {
+                self.missing.insert(index);
+            }
+            self.chunks[index] = Some(chunk);
+        }
+
+        while self.chunks.get(self.known).is_some_and(Option::is_some) {
+            self.known += 1;
+        }
+
+        if self.end.is_none() {
+            self.end = announcement.end;
+        }
+
+        // Chunks before the window can no longer be retransmitted.
+        if let Some(&index) = self.missing.first()
+            && index < announcement.window_start
+        {
+            bail!("Chunk {} of the stream is no longer available", index);
+        }
+        if self.known < announcement.window_start {
+            bail!(
+                "Chunk {} of the stream was never announced to this client",
+                self.known
+            );
+        }
+
+        Ok(())
+    }
+}
+
+async fn stream_receiver(
+    state: &Arc<ClientState>,
+    format: &SetOnce<StreamFormat>,
+    to_disk: Sender<StreamBlock>,
+) -> Result<()> {
+    let server = state.server.get().unwrap();
+    let Some(group) = server.metadata_group else {
+        bail!("Server streams without announcing its chunks");
+    };
+    if state.config.passive {
+        bail!("Streams cannot be received passively, the server waits for clients to join");
+    }
+
+    let announcements = new_receiver_multicast_socket(group, state.interface_id).await?;
+    let socket = new_receiver_multicast_socket(server.transfer_socket, state.interface_id).await?;
+
+    let mut requester = Requester::new(state, server).await?;
+
+    let mut stream = StreamChunks::default();
+    let mut assemblers = BTreeMap::<usize, Assembler>::new();
+    let mut buf = vec![0u8; 2500 - 40 - 8];
+    let mut announcement_buf = vec![0u8; u16::MAX as usize];
+
+    let mut stats = ReceptionStats::default();
+    let mut next_report = Instant::now() + REPORT_INTERVAL;
+    let mut next_request = Instant::now() + REQUEST_INTERVAL;
+    let mut last_data = Instant::now();
+    // Chunk of the last data packet, and the highest one seen so far.
+    let mut current = 0;
+    let mut latest = 0;
+
+    info!("Receiving stream announcements on group {}", group);
+
+    while !stream.is_complete() {
+        let read_size = select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(()),
+            _ = sleep_until(next_report) => {
+                let now = Instant::now();
+                if let Some(report) = stats.report(now - (next_report - REPORT_INTERVAL)) {
+                    requester.report(report).await?;
+                }
+                next_report = now + REPORT_INTERVAL;
+                continue;
+            }
+            _ = sleep_until(next_request), if !stream.missing.is_empty() => {
+                let now = Instant::now();
+                next_request = now + requester.delay(REQUEST_INTERVAL);
+                // While data flows only the chunks the server moved past are
+                // asked for, they may leave the window before it pauses.
+                let idle = now >= last_data + REQUEST_INTERVAL;
+                let ranges = stream
+                    .missing
+                    .iter()
+                    .filter(|&&chunk| idle || (chunk < latest && chunk != current))
+                    .flat_map(|&chunk| {
+                        let ranges = match assemblers.get(&chunk) {
+                            Some(assembler) => assembler.missing_ranges(),
+                            None => vec![(0, stream.chunks[chunk].as_ref().unwrap().wire_size())],
+                        };
+                        ranges
+                            .into_iter()
+                            .map(move |(offset, len)| FragmentRange { chunk, offset, len })
+                    });
+                if requester.request(ranges).await? {
+                    state.progress.send_modify(|progress| progress.requests_sent += 1);
+                }
+                continue;
+            }
+            x = requester.hear() => {
+                x?;
+                continue;
+            }
+            x = announcements.recv(&mut announcement_buf) => {
+                let size = x?;
+                let Ok(signed) = postcard::from_bytes::<Signed>(&announcement_buf[0..size]) else {
+                    continue;
+                };
+                if !signed.verify(STREAM_SIGNATURE_CONTEXT, &state.config.trusted_keys) {
+                    continue;
+                }
+                let Ok(announcement) = postcard::from_bytes::<StreamAnnouncement>(signed.payload)
+                else {
+                    continue;
+                };
+                if announcement.session != server.session {
+                    continue;
+                }
+                if !format.initialized() {
+                    info!(
+                        "Receiving a stream cut into chunks of up to {} bytes",
+                        announcement.chunking.max_size()
+                    );
+                    if !state.config.trusted_keys.is_empty() && !announcement.hash.is_cryptographic() {
+                        bail!(
+                            "Stream chunks are hashed with {:?}, which does not protect against tampering",
+                            announcement.hash
+                        );
+                    }
+                    let _ = format.set(StreamFormat {
+                        chunking: announcement.chunking,
+                        hash: announcement.hash,
+                        fec: announcement.fec,
+                    });
+                }
+                stream.update(announcement, &to_disk).await?;
+                state.progress.send_modify(|progress| {
+                    progress.chunks_total = match &stream.end {
+                        Some(end) => end.chunks,
+                        None => stream.known,
+                    };
+                    progress.bytes_total = stream.end.as_ref().map_or(0, |end| end.size);
+                });
+                continue;
+            }
+            x = socket.recv(&mut buf) => x?,
+        };
+
+        let fragment = match postcard::from_bytes::<ChunkData>(&buf[0..read_size]) {
+            Ok(x) => x,
+            Err(postcard::Error::DeserializeUnexpectedEnd) => {
+                buf.resize(2 * buf.len(), 0);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if fragment.session != server.session {
+            continue;
+        }
+
+        stats.record(fragment.seq, read_size);
+        last_data = Instant::now();
+        current = fragment.chunk;
+        latest = latest.max(fragment.chunk);
+
+        let Some(format) = format.get() else {
+            continue;
+        };
+        if !stream.missing.contains(&fragment.chunk) {
+            continue;
+        }
+        let chunk = stream.chunks[fragment.chunk].as_ref().unwrap();
+
+        if !assemblers.contains_key(&fragment.chunk) {
+            while assemblers.len() > 40 {
+                assemblers.pop_first();
+            }
+
+            assemblers.insert(
+                fragment.chunk,
+                Assembler::new(chunk.wire_size(), format.fec.as_ref()),
+            );
+        }
+
+        let assembler = assemblers.get_mut(&fragment.chunk).unwrap();
+
+        if assembler
+            .add_fragment(fragment.offset, fragment.data)
+            .is_err()
+        {
+            continue;
+        }
+
+        if assembler.is_complete() {
+            let assembler = assemblers.remove(&fragment.chunk).unwrap();
+            let mut chunk_data = assembler.complete();
+
+            if chunk.compressed_size.is_some() {
+                match decompress(&chunk_data, chunk.size) {
+                    Ok(x) if x.len() == chunk.size => chunk_data = x,
+                    _ => {
+                        warn!("Corrupted chunk (cannot be decompressed), discarding");
+                        state
+                            .progress
+                            .send_modify(|progress| progress.corrupted_chunks += 1);
+                        continue;
+                    }
+                }
+            }
+
+            if format.hash.hash(&chunk_data) != chunk.hash {
+                warn!("Corrupted chunk (hash doesn't match), discarding");
+                state
+                    .progress
+                    .send_modify(|progress| progress.corrupted_chunks += 1);
+                continue;
+            }
+
+            state
+                .progress
+                .send_modify(|progress| progress.bytes_received += chunk.wire_size() as u64);
+
+            let block = StreamBlock::Data {
+                offset: chunk.locations[0].offset,
+                data: chunk_data,
+            };
+            if to_disk.send(block).await.is_err() {
+                break;
+            }
+
+            stream.missing.remove(&fragment.chunk);
+            stream.complete += 1;
+        }
+    }
+
+    if !stream.is_complete() {
+        return Ok(());
+    }
+
+    // The stream is now described like an image made of a single file, to
+    // be verified the same way.
+    let format = format
+        .get()
+        .expect("Invalid global state (stream without announcements)");
+    let end = stream
+        .end
+        .expect("Invalid global state (stream without an end)");
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let image = ImageMetadata {
+        files: Box::new([FileMetadata {
+            path: String::new(),
+            kind: FileKind::Regular { size: end.size },
+            mode: 0o644,
+            mtime_secs: mtime.as_secs() as i64,
+            mtime_nanos: mtime.subsec_nanos(),
+        }]),
+        chunks: stream.chunks.into_iter().flatten().collect(),
+        fec: format.fec,
+        chunking: format.chunking,
+        hash: format.hash,
+        digest: end.digest,
+    };
+    if image
+        .chunks
+        .iter()
+        .map(|chunk| chunk.size as u64)
+        .sum::<u64>()
+        != end.size
+    {
+        bail!("Invalid stream announcement (chunks do not add up to the size of the stream)");
+    }
+    state
+        .image
+        .set(image)
+        .expect("Invalid global state (image was already retrieved)");
+
+    Ok(())
+}
+
+/// Where the writer puts the stream.
+enum StreamSink {
+    File(TargetFile),
+    Output(OrderedOutput),
+}
+
+async fn open_target(state: &ClientState) -> Result<TargetFile> {
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&state.target)
+        .await?;
+    let block_device = is_block_device(&file.metadata().await?);
+    let direct = if block_device {
+        DirectWriter::open(&state.target)
+            .inspect_err(|e| {
+                warn!(
+                    "Unable to bypass the page cache of {} ({})",
+                    state.target.display(),
+                    e
+                )
+            })
+            .ok()
+    } else {
+        None
+    };
+    Ok(TargetFile {
+        index: 0,
+        file,
+        block_device,
+        direct,
+    })
+}
+
+/// Writes the stream to the target, or in order to `output`. Returns the
+/// digest of what was written to `output`.
+async fn stream_writer(
+    state: &Arc<ClientState>,
+    format: &SetOnce<StreamFormat>,
+    mut from_net: Receiver<StreamBlock>,
+    output: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+) -> Result<Option<Digest>> {
+    let mut sink = match output {
+        Some(output) => {
+            let format = select! {
+                biased;
+                _ = state.token.cancelled() => return Ok(None),
+                x = format.wait() => x,
+            };
+            StreamSink::Output(OrderedOutput::new(output, format.hash))
+        }
+        None => StreamSink::File(open_target(state).await?),
+    };
+
+    let mut count = 0u64;
+    let mut last_count = count;
+    let mut time = Instant::now();
+
+    loop {
+        let block = select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(None),
+            _ = sleep_until(time + Duration::from_secs(1)) => {
+                let now = Instant::now();
+                let rate = 8.0 * (count - last_count) as f32 / (now - time).as_secs_f32();
+                info!(
+                    "Receiving stream... {} bytes written ({} Mb/s)",
+                    count,
+                    rate / (1024f32 * 1024f32)
+                );
+                state.progress.send_modify(|progress| progress.rate = rate as u64);
+                time = now;
+                last_count = count;
+                continue;
+            },
+            x = from_net.recv() => if let Some(x) = x { x } else { break },
+        };
+        count += match (&mut sink, block) {
+            (StreamSink::Output(output), block) => output.write(block).await?,
+            (StreamSink::File(target), StreamBlock::Data { offset, data }) => {
+                target.write_at(offset, &data).await?;
+                data.len() as u64
+            }
+            (StreamSink::File(target), StreamBlock::Zeros { offset, len }) => {
+                zero_range(&mut target.file, offset, len, state.config.sparse).await?;
+                len as u64
+            }
+        };
+        state.progress.send_modify(|progress| {
+            progress.bytes_written = count;
+            progress.chunks_complete += 1;
+        });
+    }
+
+    let Some(image) = state.image.get() else {
+        return Ok(None);
+    };
+    match sink {
+        StreamSink::File(target) => {
+            // Whatever the target held beyond the end of the stream is cut off.
+            if !target.block_device {
+                target.file.set_len(image.size()).await?;
+            }
+            target.close().await?;
+            Ok(None)
+        }
+        StreamSink::Output(output) => output.finish(image.size()).await.map(Some),
+    }
+}
+
+/// Receives a stream as it is announced, until its end.
+pub async fn stream_transfer(
+    state: &Arc<ClientState>,
+    output: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+) -> Result<()> {
+    let (sx, rx) = channel(128);
+    let format = SetOnce::new();
+
+    state
+        .progress
+        .send_modify(|progress| progress.phase = ClientPhase::Receiving);
+
+    let (_, written) = try_join!(
+        stream_receiver(state, &format, sx),
+        stream_writer(state, &format, rx, output)
+    )?;
+
+    if state.image.get().is_some() && !state.token.is_cancelled() {
+        verify_image(state, written).await?;
+        notify_completion(state).await?;
+        state
+            .progress
+            .send_modify(|progress| progress.phase = ClientPhase::Done);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChunkLocation, SessionId};
+
+    fn announcement(window_start: usize, first: usize, count: usize) -> StreamAnnouncement {
+        StreamAnnouncement {
+            session: SessionId {
+                session: 1,
+                image: 0,
+            },
+            chunking: Chunking::Fixed { size: 1024 },
+            hash: HashAlgorithm::Blake3,
+            fec: None,
+            window_start,
+            first,
+            chunks: (0..count)
+                .map(|i| ChunkMetadata {
+                    locations: vec![ChunkLocation {
+                        file: 0,
+                        offset: (i * 1024) as u64,
+                    }],
+                    size: 1024,
+                    compressed_size: None,
+                    zero: false,
+                    hash: Digest::new(),
+                })
+                .collect(),
+            end: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn records_announced_chunks() {
+        let (sender, _receiver) = channel(1);
+        let mut stream = StreamChunks::default();
+        stream.update(announcement(0, 2, 2), &sender).await.unwrap();
+        assert_eq!(stream.known, 0);
+        stream.update(announcement(0, 0, 3), &sender).await.unwrap();
+        assert_eq!(stream.known, 4);
+        assert_eq!(stream.missing, BTreeSet::from([0, 1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn rejects_chunks_beyond_the_window() {
+        let (sender, _receiver) = channel(1);
+        let mut stream = StreamChunks::default();
+        for announcement in [
+            announcement(0, MAX_STREAM_WINDOW, 1),
+            announcement(0, usize::MAX, 1),
+            announcement(usize::MAX, usize::MAX - 1, 1),
+        ] {
+            assert!(stream.update(announcement, &sender).await.is_err());
+        }
+        assert!(stream.chunks.is_empty());
+    }
+}
@@ -0,0 +1,1036 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{ErrorKind, SeekFrom},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Error, Result, bail};
+use log::{info, warn};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    select,
+    sync::mpsc::{Receiver, Sender, channel},
+    time::{Instant, sleep, sleep_until},
+    try_join,
+};
+use zstd::bulk::decompress;
+
+use super::{
+    ClientPhase, ClientState,
+    chunk::Assembler,
+    device::{DirectWriter, device_size, file_size, is_block_device},
+    output::{OrderedOutput, StreamBlock},
+    request::{REQUEST_INTERVAL, Requester},
+    seed::seed_chunks,
+    sparse::zero_range,
+    stream::stream_transfer,
+    tree::{check_tree, finish_tree, open_options, prepare_tree, target_path},
+};
+use crate::{
+    ChunkData, ClientMessage, ClientPacket, DISCOVERY_SIGNATURE_CONTEXT, FileKind, FragmentRange,
+    ImageMetadata, METADATA_SIGNATURE_CONTEXT, MetadataBlock, ReceptionReport, ServerDiscovery,
+    Signed, hash::Digest, net::new_receiver_multicast_socket,
+};
+
+pub async fn spawn<T, R>(future: T) -> Result<R>
+where
+    T: Future<Output = Result<R>> + Send + 'static,
+    R: Send + 'static,
+{
+    tokio::spawn(future).await?
+}
+
+pub async fn server_discovery(state: Arc<ClientState>) -> Result<()> {
+    let socket =
+        new_receiver_multicast_socket(state.config.discovery_socket, state.interface_id).await?;
+
+    let token = state.token.clone();
+    // Discoveries vary in size, any datagram fits.
+    let mut buf = vec![0u8; u16::MAX as usize];
+
+    info!(
+        "Listening for server discovery on interface {} on group {}",
+        state.interface.name, state.config.discovery_socket
+    );
+
+    loop {
+        let size = select! {
+            biased;
+            _ = token.cancelled() => { return Err(Error::msg("Server discovery cancelled")); },
+            size = socket.recv(&mut buf) => size?,
+        };
+
+        let Ok(signed) = postcard::from_bytes::<Signed>(&buf[0..size]) else {
+            continue;
+        };
+
+        if !signed.verify(DISCOVERY_SIGNATURE_CONTEXT, &state.config.trusted_keys) {
+            warn!("Ignoring server discovery that is not signed by a trusted key");
+            continue;
+        }
+
+        if let Ok(mut server) = postcard::from_bytes::<ServerDiscovery>(signed.payload)
+            && server
+                .metadata_socket
+                .iter()
+                .chain(&server.metadata_group)
+                .all(|x| x.is_ipv6() == state.unicast.is_ipv6())
+            && server.request_socket.is_ipv6() == state.unicast.is_ipv6()
+            && server.transfer_socket.is_ipv6() == state.unicast.is_ipv6()
+        {
+            info!(
+                "Discovered server for transfer on socket {}",
+                server.transfer_socket
+            );
+
+            for socket in [&mut server.transfer_socket, &mut server.request_socket]
+                .into_iter()
+                .chain(&mut server.metadata_socket)
+            {
+                if let SocketAddr::V6(socket) = socket
+                    && socket.ip().is_unicast_link_local()
+                {
+                    socket.set_scope_id(state.interface.index);
+                }
+            }
+
+            state
+                .progress
+                .send_modify(|progress| progress.server = Some(server.request_socket));
+            state
+                .server
+                .set(server)
+                .expect("Invalid global state (server was already discovered).");
+            return Ok(());
+        }
+    }
+}
+
+/// Time without metadata blocks after which TCP is used instead, when the
+/// server offers it.
+const METADATA_CAROUSEL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Blocks announcing larger metadata are ignored, rather than allocating
+/// whatever anyone on the network claims.
+const MAX_METADATA_SIZE: usize = 1 << 30;
+
+/// Puts together the signed image metadata from the blocks carouselled on
+/// `group`. Gives up if `fallback` is set and no block arrives for a while.
+async fn receive_metadata_blocks(
+    state: &Arc<ClientState>,
+    server: &ServerDiscovery,
+    group: SocketAddr,
+    fallback: bool,
+) -> Result<Option<Vec<u8>>> {
+    let socket = new_receiver_multicast_socket(group, state.interface_id).await?;
+
+    info!("Retrieving image metadata from group {}", group);
+
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut metadata: Vec<u8> = Vec::new();
+    let mut received: BTreeSet<usize> = BTreeSet::new();
+    let mut received_size = 0;
+    let mut last_block = Instant::now();
+
+    loop {
+        let size = select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(None),
+            _ = sleep_until(last_block + METADATA_CAROUSEL_TIMEOUT), if fallback => {
+                info!(
+                    "No image metadata received on group {} for {} seconds, falling back to TCP",
+                    group,
+                    METADATA_CAROUSEL_TIMEOUT.as_secs()
+                );
+                return Ok(None);
+            },
+            size = socket.recv(&mut buf) => size?,
+        };
+
+        let Ok(block) = postcard::from_bytes::<MetadataBlock>(&buf[0..size]) else {
+            continue;
+        };
+        if block.session != server.session
+            || block.size > MAX_METADATA_SIZE
+            || block.offset.saturating_add(block.data.len()) > block.size
+        {
+            continue;
+        }
+        last_block = Instant::now();
+
+        if metadata.len() != block.size {
+            metadata = vec![0u8; block.size];
+            received.clear();
+            received_size = 0;
+        }
+        if !received.insert(block.offset) {
+            continue;
+        }
+        metadata[block.offset..block.offset + block.data.len()].copy_from_slice(block.data);
+        received_size += block.data.len();
+
+        if received_size == metadata.len() {
+            if blake3::hash(&metadata).as_bytes() == &server.metadata_digest {
+                return Ok(Some(metadata));
+            }
+            warn!(
+                "Image metadata received on group {} is corrupted, retrying",
+                group
+            );
+            metadata.clear();
+        }
+    }
+}
+
+pub async fn metadata_transfer(state: Arc<ClientState>) -> Result<()> {
+    let token = state.token.clone();
+
+    let server = select! {
+        biased;
+        _ = token.cancelled() => { return Ok(()); },
+        x = state.server.wait() => x,
+    };
+
+    // Streams are announced bit by bit while they are received.
+    if server.stream {
+        return Ok(());
+    }
+
+    state
+        .progress
+        .send_modify(|progress| progress.phase = ClientPhase::FetchingMetadata);
+
+    let metadata_socket = server.metadata_socket.filter(|_| !state.config.passive);
+    if state.config.passive && server.metadata_group.is_none() {
+        bail!("Server does not carousel its image metadata, which passive clients need");
+    }
+
+    let mut buf = None;
+
+    if let Some(group) = server.metadata_group
+        && !(state.config.tcp_metadata && metadata_socket.is_some())
+    {
+        buf = receive_metadata_blocks(&state, server, group, metadata_socket.is_some()).await?;
+    }
+
+    if token.is_cancelled() {
+        return Ok(());
+    }
+
+    let buf = match buf {
+        Some(buf) => buf,
+        None => {
+            let Some(address) = metadata_socket else {
+                bail!("Server does not serve image metadata over TCP");
+            };
+
+            info!("Connecting to image metadata server at {}", address);
+            let mut socket = select! {
+                biased;
+                _ = token.cancelled() => { return Ok(()); },
+                x = TcpStream::connect(address) => x?,
+            };
+
+            info!("Retrieving image metadata from server");
+
+            let mut buf = Vec::<u8>::new();
+            select! {
+                biased;
+                _ = token.cancelled() => { return Ok(()); },
+                x = socket.read_to_end(&mut buf) => x?,
+            };
+
+            if blake3::hash(&buf).as_bytes() != &server.metadata_digest {
+                bail!("Received metadata does not match the digest announced by the server");
+            }
+            buf
+        }
+    };
+
+    let signed = postcard::from_bytes::<Signed>(&buf)?;
+
+    if !signed.verify(METADATA_SIGNATURE_CONTEXT, &state.config.trusted_keys) {
+        bail!("Image metadata is not signed by a trusted key");
+    }
+
+    let metadata = postcard::from_bytes::<ImageMetadata>(signed.payload)?;
+    if metadata.content_id() != server.session.image {
+        bail!("Received metadata does not belong to the discovered image");
+    }
+    check_tree(&metadata)?;
+    if !state.config.trusted_keys.is_empty() && !metadata.hash.is_cryptographic() {
+        bail!(
+            "Image chunks are hashed with {:?}, which does not protect against tampering",
+            metadata.hash
+        );
+    }
+    info!(
+        "Received metadata for an image of size {} bytes in {} files subdivided into {} chunks",
+        metadata.size(),
+        metadata.files.len(),
+        metadata.chunks.len()
+    );
+    state
+        .image
+        .set(metadata)
+        .expect("Invalid global state (image was already retrieved)");
+    Ok(())
+}
+
+async fn find_missing_chunks(state: &Arc<ClientState>) -> Result<BTreeSet<usize>> {
+    let image = state.image.wait().await;
+    let mut missing: BTreeSet<usize> = (0..image.chunks.len()).collect();
+
+    if state.config.force {
+        return Ok(missing);
+    }
+
+    info!("Checking existing data in {}", state.target.display());
+
+    let mut file: Option<(usize, Option<(File, u64)>)> = None;
+    let mut buf: Vec<u8> = Vec::new();
+
+    'chunks: for (i, chunk) in image.chunks.iter().enumerate() {
+        if state.token.is_cancelled() {
+            break;
+        }
+
+        // A chunk is present only if it is found everywhere it appears.
+        for location in chunk.locations.iter() {
+            let file = match file {
+                Some((index, ref mut file)) if index == location.file => file,
+                _ => {
+                    let entry = &image.files[location.file];
+                    let path = target_path(&state.target, entry)?;
+                    let x = match open_options(entry).read(true).open(&path).await {
+                        Ok(x) => {
+                            let size = file_size(&x).await?;
+                            Some((x, size))
+                        }
+                        Err(e) if e.kind() == ErrorKind::NotFound => None,
+                        Err(e) => return Err(e.into()),
+                    };
+                    &mut file.insert((location.file, x)).1
+                }
+            };
+            let Some((file, size)) = file else {
+                continue 'chunks;
+            };
+
+            // Seeking past the end of block devices fails.
+            if location.offset + chunk.size as u64 > *size {
+                continue 'chunks;
+            }
+
+            buf.resize(chunk.size, 0);
+            file.seek(SeekFrom::Start(location.offset)).await?;
+            match file.read_exact(&mut buf).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue 'chunks,
+                Err(e) => return Err(e.into()),
+            }
+
+            let present = if chunk.zero {
+                buf.iter().all(|&x| x == 0)
+            } else {
+                image.hash.hash(&buf) == chunk.hash
+            };
+            if !present {
+                continue 'chunks;
+            }
+        }
+
+        missing.remove(&i);
+    }
+
+    info!(
+        "{} of {} chunks are already present on disk",
+        image.chunks.len() - missing.len(),
+        image.chunks.len()
+    );
+
+    Ok(missing)
+}
+
+pub fn unicast_bind_address(state: &ClientState) -> SocketAddr {
+    match state.unicast {
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            0,
+            0,
+            if ip.is_unicast_link_local() {
+                state.interface.index
+            } else {
+                0
+            },
+        )),
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, 0)),
+    }
+}
+
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reception statistics of the transfer socket since the last report.
+#[derive(Default)]
+pub struct ReceptionStats {
+    packets: u64,
+    bytes: u64,
+    seq: Option<(u64, u64)>,
+}
+
+impl ReceptionStats {
+    pub fn record(&mut self, seq: u64, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+        self.seq = Some(match self.seq {
+            Some((first, last)) => (first.min(seq), last.max(seq)),
+            None => (seq, seq),
+        });
+    }
+
+    pub fn report(&mut self, elapsed: Duration) -> Option<ReceptionReport> {
+        let stats = std::mem::take(self);
+        let (first, last) = stats.seq?;
+        let expected = last - first + 1;
+        Some(ReceptionReport {
+            loss: 1.0 - (stats.packets.min(expected) as f32 / expected as f32),
+            goodput: (8.0 * stats.bytes as f64 / elapsed.as_secs_f64()) as u64,
+        })
+    }
+}
+
+/// Whether `chunk` starts less than `window` bytes after the first of the
+/// `missing` ones, always the case without a window.
+fn within_window(
+    image: &ImageMetadata,
+    window: Option<u64>,
+    missing: &BTreeSet<usize>,
+    chunk: usize,
+) -> bool {
+    match (window, missing.first()) {
+        (Some(window), Some(&first)) => {
+            image.chunks[chunk].locations[0].offset
+                < image.chunks[first].locations[0].offset + window
+        }
+        _ => true,
+    }
+}
+
+/// Receives the `missing` chunks. With a `window`, only chunks starting less
+/// than `window` bytes after the first missing one are received and requested,
+/// which bounds what an ordered output has to hold.
+async fn chunk_receiver(
+    state: &Arc<ClientState>,
+    mut missing: BTreeSet<usize>,
+    window: Option<u64>,
+    to_disk: Sender<(usize, Vec<u8>)>,
+) -> Result<()> {
+    let server = state.server.wait().await;
+    let image = state.image.get().unwrap();
+    let within_window =
+        |missing: &BTreeSet<usize>, chunk| within_window(image, window, missing, chunk);
+    let socket = new_receiver_multicast_socket(server.transfer_socket, state.interface_id).await?;
+
+    // Passive clients never send anything, they wait for the server to send
+    // the chunks they miss again.
+    let mut requester = if state.config.passive {
+        None
+    } else {
+        Some(Requester::new(state, server).await?)
+    };
+
+    let mut assemblers = BTreeMap::<usize, Assembler>::new();
+    let mut buf = vec![0u8; 2500 - 40 - 8];
+
+    let mut stats = ReceptionStats::default();
+    let mut next_report = Instant::now() + REPORT_INTERVAL;
+    let mut next_request = Instant::now() + REQUEST_INTERVAL;
+
+    while !missing.is_empty() {
+        let read_size = select! {
+            biased;
+            _ = state.token.cancelled() => break,
+            _ = sleep_until(next_report), if requester.is_some() => {
+                let now = Instant::now();
+                if let Some(report) = stats.report(now - (next_report - REPORT_INTERVAL)) {
+                    requester.as_mut().unwrap().report(report).await?;
+                }
+                next_report = now + REPORT_INTERVAL;
+                continue;
+            }
+            _ = sleep_until(next_request), if requester.is_some() => {
+                let requester = requester.as_mut().unwrap();
+                let ranges = missing
+                    .iter()
+                    .take_while(|&&chunk| within_window(&missing, chunk))
+                    .flat_map(|&chunk| {
+                        let ranges = match assemblers.get(&chunk) {
+                            Some(assembler) => assembler.missing_ranges(),
+                            None => vec![(0, image.chunks[chunk].wire_size())],
+                        };
+                        ranges
+                            .into_iter()
+                            .map(move |(offset, len)| FragmentRange { chunk, offset, len })
+                    });
+                if requester.request(ranges).await? {
+                    state.progress.send_modify(|progress| progress.requests_sent += 1);
+                }
+                next_request = Instant::now() + requester.delay(REQUEST_INTERVAL);
+                continue;
+            }
+            x = async { requester.as_mut().unwrap().hear().await }, if requester.is_some() => {
+                x?;
+                continue;
+            }
+            x = socket.recv(&mut buf) => x?,
+        };
+
+        let fragment = match postcard::from_bytes::<ChunkData>(&buf[0..read_size]) {
+            Ok(x) => x,
+            Err(postcard::Error::DeserializeUnexpectedEnd) => {
+                buf.resize(2 * buf.len(), 0);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if fragment.session != server.session {
+            continue;
+        }
+
+        stats.record(fragment.seq, read_size);
+
+        if !missing.contains(&fragment.chunk) || !within_window(&missing, fragment.chunk) {
+            continue;
+        }
+
+        let chunk = &image.chunks[fragment.chunk];
+
+        if !assemblers.contains_key(&fragment.chunk) {
+            while assemblers.len() > 40 {
+                assemblers.pop_first();
+            }
+
+            assemblers.insert(
+                fragment.chunk,
+                Assembler::new(chunk.wire_size(), image.fec.as_ref()),
+            );
+        }
+
+        let assembler = assemblers.get_mut(&fragment.chunk).unwrap();
+
+        if assembler
+            .add_fragment(fragment.offset, fragment.data)
+            .is_err()
+        {
+            continue;
+        }
+
+        if assembler.is_complete() {
+            let assembler = assemblers.remove(&fragment.chunk).unwrap();
+            let mut chunk_data = assembler.complete();
+
+            if chunk.compressed_size.is_some() {
+                match decompress(&chunk_data, chunk.size) {
+                    Ok(x) if x.len() == chunk.size => chunk_data = x,
+                    _ => {
+                        warn!("Corrupted chunk (cannot be decompressed), discarding");
+                        state
+                            .progress
+                            .send_modify(|progress| progress.corrupted_chunks += 1);
+                        continue;
+                    }
+                }
+            }
+
+            if image.hash.hash(&chunk_data) != chunk.hash {
+                warn!("Corrupted chunk (hash doesn't match), discarding");
+                state
+                    .progress
+                    .send_modify(|progress| progress.corrupted_chunks += 1);
+                continue;
+            }
+
+            state
+                .progress
+                .send_modify(|progress| progress.bytes_received += chunk.wire_size() as u64);
+
+            if to_disk.send((fragment.chunk, chunk_data)).await.is_err() {
+                break;
+            }
+
+            missing.remove(&fragment.chunk);
+        }
+    }
+
+    Ok(())
+}
+
+/// A file of the target being written by the disk writer.
+pub struct TargetFile {
+    pub index: usize,
+    pub file: File,
+    /// Block devices are synced once done.
+    pub block_device: bool,
+    /// Bypasses the page cache for block devices, when supported.
+    pub direct: Option<DirectWriter>,
+}
+
+impl TargetFile {
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if let Some(direct) = &mut self.direct
+            && direct.write_at(offset, data).await?
+        {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        let mut written: usize = 0;
+        while written < data.len() {
+            let x = self.file.write(&data[written..]).await?;
+            if x == 0 {
+                bail!("Failed to write chunk to disk");
+            }
+            written += x;
+        }
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        self.file.flush().await?;
+        if self.block_device {
+            self.file.sync_all().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file with the given index, reusing the one in `cache` if it
+/// matches. The previous file is closed before being replaced.
+async fn cached_file<'a>(
+    cache: &'a mut Option<TargetFile>,
+    target: &Path,
+    image: &ImageMetadata,
+    devices: &BTreeSet<usize>,
+    index: usize,
+) -> Result<&'a mut TargetFile> {
+    if let Some(file) = cache.take_if(|file| file.index != index) {
+        file.close().await?;
+    }
+    if cache.is_none() {
+        let path = target_path(target, &image.files[index])?;
+        let block_device = devices.contains(&index);
+        let direct = if block_device {
+            DirectWriter::open(&path)
+                .inspect_err(|e| {
+                    warn!(
+                        "Unable to bypass the page cache of {} ({})",
+                        path.display(),
+                        e
+                    )
+                })
+                .ok()
+        } else {
+            None
+        };
+        *cache = Some(TargetFile {
+            index,
+            file: open_options(&image.files[index])
+                .write(true)
+                .open(&path)
+                .await?,
+            block_device,
+            direct,
+        });
+    }
+    Ok(cache.as_mut().unwrap())
+}
+
+async fn disk_writer(
+    state: &Arc<ClientState>,
+    mut count: u64,
+    zeros: Vec<usize>,
+    mut from_net: Receiver<(usize, Vec<u8>)>,
+) -> Result<()> {
+    let image = state.image.wait().await;
+    let image_size = image.size();
+
+    prepare_tree(&state.target, image)?;
+
+    let mut devices = BTreeSet::new();
+
+    for (index, file) in image.files.iter().enumerate() {
+        let FileKind::Regular { size } = file.kind else {
+            continue;
+        };
+        let path = target_path(&state.target, file)?;
+        let file = open_options(file)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+
+        if is_block_device(&file.metadata().await?) {
+            let device_size = device_size(&file).await?;
+            if device_size < size {
+                bail!(
+                    "{} is a block device of {} bytes, which cannot fit an image of {} bytes",
+                    path.display(),
+                    device_size,
+                    size
+                );
+            }
+            devices.insert(index);
+            continue;
+        }
+
+        file.set_len(size).await.unwrap_or_else(|e| {
+            warn!("Unable to resize {} ({})", path.display(), e);
+        });
+
+        if file.metadata().await?.len() < size {
+            bail!("{} too small to fit image", path.display());
+        }
+    }
+
+    let mut file: Option<TargetFile> = None;
+
+    // Chunks that only contain zeros are never transmitted, fill them in
+    // before the others arrive.
+    for i in zeros {
+        if state.token.is_cancelled() {
+            return Ok(());
+        }
+        let chunk = &image.chunks[i];
+        for location in chunk.locations.iter() {
+            let f = cached_file(&mut file, &state.target, image, &devices, location.file).await?;
+            zero_range(
+                &mut f.file,
+                location.offset,
+                chunk.size,
+                state.config.sparse,
+            )
+            .await?;
+        }
+        count += chunk.image_size();
+        state.progress.send_modify(|progress| {
+            progress.bytes_written = count;
+            progress.chunks_complete += 1;
+        });
+    }
+
+    let mut last_count: u64 = count;
+    let mut time = Instant::now();
+
+    loop {
+        let (index, data) = select! {
+            biased;
+            _ = state.token.cancelled() => { return Ok(()) },
+            _ = sleep_until(time + Duration::from_secs(1)) => {
+                let now = Instant::now();
+                let rate = 8.0 * (count - last_count) as f32 / (now - time).as_secs_f32();
+                info!(
+                    "Receiving image... {} bytes left ({} Mb/s)",
+                    image_size - count,
+                    rate / (1024f32 * 1024f32)
+                );
+                state.progress.send_modify(|progress| progress.rate = rate as u64);
+                time = now;
+                last_count = count;
+                continue;
+            },
+            x = from_net.recv() => if let Some(x) = x { x } else { break },
+        };
+        let chunk = &image.chunks[index];
+        for location in chunk.locations.iter() {
+            cached_file(&mut file, &state.target, image, &devices, location.file)
+                .await?
+                .write_at(location.offset, &data)
+                .await?;
+        }
+        count += chunk.image_size();
+        state.progress.send_modify(|progress| {
+            progress.bytes_written = count;
+            progress.chunks_complete += 1;
+        });
+    }
+
+    if let Some(file) = file {
+        file.close().await?;
+    }
+
+    if !state.token.is_cancelled() {
+        finish_tree(&state.target, image)?;
+    }
+
+    Ok(())
+}
+
+/// Writes an image made of a single file to `output` in order, returns the
+/// digest of what was written.
+async fn output_writer(
+    state: &Arc<ClientState>,
+    zeros: Vec<usize>,
+    mut from_net: Receiver<(usize, Vec<u8>)>,
+    output: Box<dyn AsyncWrite + Send + Unpin>,
+) -> Result<Option<Digest>> {
+    let image = state.image.wait().await;
+    let image_size = image.size();
+    let mut output = OrderedOutput::new(output, image.hash);
+    let window = state.config.reorder_buffer;
+    // Chunks received but not yet written everywhere they appear, by the
+    // offset of the next place they are written to, with its position in
+    // their locations.
+    let mut held: BTreeMap<u64, (usize, usize, Vec<u8>)> = BTreeMap::new();
+    let mut count = 0;
+
+    for i in zeros {
+        let chunk = &image.chunks[i];
+        for location in chunk.locations.iter() {
+            count += output
+                .write(StreamBlock::Zeros {
+                    offset: location.offset,
+                    len: chunk.size,
+                })
+                .await?;
+        }
+        state
+            .progress
+            .send_modify(|progress| progress.chunks_complete += 1);
+    }
+    state
+        .progress
+        .send_modify(|progress| progress.bytes_written = count);
+
+    let mut last_count: u64 = count;
+    let mut time = Instant::now();
+
+    loop {
+        let (index, data) = select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(None),
+            _ = sleep_until(time + Duration::from_secs(1)) => {
+                let now = Instant::now();
+                let rate = 8.0 * (count - last_count) as f32 / (now - time).as_secs_f32();
+                info!(
+                    "Receiving image... {} bytes left ({} Mb/s)",
+                    image_size - count,
+                    rate / (1024f32 * 1024f32)
+                );
+                state.progress.send_modify(|progress| progress.rate = rate as u64);
+                time = now;
+                last_count = count;
+                continue;
+            },
+            x = from_net.recv() => if let Some(x) = x { x } else { break },
+        };
+        // The chunk comes within the reorder buffer, but it may also appear
+        // further away. It is held until those places come within it too,
+        // rather than holding a copy for each of them.
+        held.insert(image.chunks[index].locations[0].offset, (index, 0, data));
+        while let Some(entry) = held.first_entry()
+            && *entry.key() < output.position() + window
+        {
+            let (index, i, data) = entry.remove();
+            let locations = &image.chunks[index].locations;
+            if let Some(next) = locations.get(i + 1) {
+                held.insert(next.offset, (index, i + 1, data.clone()));
+            }
+            count += output
+                .write(StreamBlock::Data {
+                    offset: locations[i].offset,
+                    data,
+                })
+                .await?;
+        }
+        state.progress.send_modify(|progress| {
+            progress.bytes_written = count;
+            progress.chunks_complete += 1;
+        });
+    }
+
+    if state.token.is_cancelled() {
+        return Ok(None);
+    }
+
+    output.finish(image_size).await.map(Some)
+}
+
+/// Checks the digest of the image, as `written` to an output stream or else
+/// by reading the target back.
+pub async fn verify_image(state: &Arc<ClientState>, written: Option<Digest>) -> Result<()> {
+    let image = state.image.get().unwrap();
+
+    state
+        .progress
+        .send_modify(|progress| progress.phase = ClientPhase::Verifying);
+
+    info!("Verifying image digest");
+
+    let mut hasher = image.hash.hasher();
+    let mut buf = vec![0u8; 1024 * 1024];
+
+    for file in image.files.iter().filter(|_| written.is_none()) {
+        let FileKind::Regular { size } = file.kind else {
+            continue;
+        };
+        let mut file = open_options(file)
+            .read(true)
+            .open(target_path(&state.target, file)?)
+            .await?
+            .take(size);
+        loop {
+            let n = select! {
+                biased;
+                _ = state.token.cancelled() => return Ok(()),
+                x = file.read(&mut buf) => x?,
+            };
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[0..n]);
+        }
+    }
+
+    let digest = written.unwrap_or_else(|| hasher.finalize());
+    if digest != image.digest {
+        bail!(
+            "Image digest mismatch (expected {:?} {}, found {})",
+            image.hash,
+            hex::encode(&image.digest),
+            hex::encode(&digest)
+        );
+    }
+
+    info!(
+        "Image digest verified ({:?} {})",
+        image.hash,
+        hex::encode(&digest)
+    );
+    state.progress.send_modify(|progress| {
+        progress.digest = Some(format!("{:?}:{}", image.hash, hex::encode(&digest)).to_lowercase())
+    });
+
+    Ok(())
+}
+
+pub async fn notify_completion(state: &Arc<ClientState>) -> Result<()> {
+    if state.config.passive {
+        return Ok(());
+    }
+
+    let server = state.server.get().unwrap();
+    let socket = UdpSocket::bind(unicast_bind_address(state)).await?;
+    socket.connect(server.request_socket).await?;
+
+    let packet = postcard::to_allocvec(&ClientPacket {
+        session: server.session,
+        client: state.id,
+        message: ClientMessage::Completed,
+    })?;
+
+    // Completions are not acknowledged, repeat them in case some get lost.
+    // The server may already be gone after the first one, which is fine.
+    for _ in 0..3 {
+        if socket.send(&packet).await.is_err() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    Ok(())
+}
+
+pub async fn chunk_transfer(
+    state: Arc<ClientState>,
+    output: Option<Box<dyn AsyncWrite + Send + Unpin>>,
+) -> Result<()> {
+    let server = select! {
+        biased;
+        _ = state.token.cancelled() => return Ok(()),
+        x = state.server.wait() => x,
+    };
+
+    if server.stream {
+        return stream_transfer(&state, output).await;
+    }
+
+    let (sx, rx) = channel(128);
+
+    let image = select! {
+        biased;
+        _ = state.token.cancelled() => return Ok(()),
+        x = state.image.wait() => x,
+    };
+    let mut missing = if output.is_some() {
+        if !matches!(&*image.files, [file] if matches!(file.kind, FileKind::Regular { .. })) {
+            bail!("Only images made of a single file can be written to an output stream");
+        }
+        (0..image.chunks.len()).collect()
+    } else {
+        find_missing_chunks(&state).await?
+    };
+    let present: u64 = image
+        .chunks
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !missing.contains(i))
+        .map(|(_, chunk)| chunk.image_size())
+        .sum();
+
+    state.progress.send_modify(|progress| {
+        progress.phase = ClientPhase::Receiving;
+        progress.bytes_total = image.size();
+        progress.bytes_written = present;
+        progress.chunks_total = image.chunks.len();
+        progress.chunks_complete = image.chunks.len() - missing.len();
+    });
+
+    let zeros: Vec<usize> = missing
+        .iter()
+        .copied()
+        .filter(|&i| image.chunks[i].zero)
+        .collect();
+    for i in zeros.iter() {
+        missing.remove(i);
+    }
+
+    let window = output.is_some().then_some(state.config.reorder_buffer);
+    let receiver = async {
+        if !state.config.seeds.is_empty() {
+            // Seeds are read in their own order, only chunks that could be
+            // received right away are taken from them.
+            let wanted = missing
+                .iter()
+                .copied()
+                .filter(|&i| within_window(image, window, &missing, i))
+                .collect();
+            let found = seed_chunks(&state, &wanted, sx.clone()).await?;
+            missing.retain(|i| !found.contains(i));
+        }
+        chunk_receiver(&state, missing, window, sx).await
+    };
+    let writer = async {
+        match output {
+            Some(output) => output_writer(&state, zeros, rx, output).await,
+            None => disk_writer(&state, present, zeros, rx).await.map(|_| None),
+        }
+    };
+
+    let (_, written) = try_join!(receiver, writer)?;
+
+    if !state.token.is_cancelled() {
+        verify_image(&state, written).await?;
+        notify_completion(&state).await?;
+        state
+            .progress
+            .send_modify(|progress| progress.phase = ClientPhase::Done);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, FileTimes},
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use log::warn;
+use tokio::fs::OpenOptions;
+
+use crate::{FileKind, FileMetadata, ImageMetadata};
+
+/// Path where `file` is stored, given the target of the transfer. Fails if a
+/// directory on the way is a link, which could lead outside of the target.
+/// The target itself may be one.
+pub fn target_path(target: &Path, file: &FileMetadata) -> Result<PathBuf> {
+    let mut out = target.to_path_buf();
+    if file.path.is_empty() {
+        return Ok(out);
+    }
+    for (i, name) in file.path.split('/').enumerate() {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(x)), None) if x == name => {}
+            _ => bail!("Refusing to write outside of the target ({})", file.path),
+        }
+        if i > 0 && out.is_symlink() {
+            bail!(
+                "Refusing to write through link {} ({})",
+                out.display(),
+                file.path
+            );
+        }
+        out.push(name);
+    }
+    Ok(out)
+}
+
+/// Checks that no entry of the image lies beneath one of its links, where it
+/// would be written to wherever the link points.
+pub fn check_tree(image: &ImageMetadata) -> Result<()> {
+    let links: HashSet<&str> = image
+        .files
+        .iter()
+        .filter(|file| matches!(file.kind, FileKind::Symlink { .. }))
+        .map(|file| file.path.as_str())
+        .collect();
+    for file in image.files.iter() {
+        let mut path = file.path.as_str();
+        while !path.is_empty() {
+            path = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            if links.contains(path) {
+                bail!("Refusing to write beneath link {:?} ({})", path, file.path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Options to open a file of the tree with, which do not follow a link in
+/// place of the file. The root of the image is opened as is, it may be a
+/// link to a device.
+pub fn open_options(file: &FileMetadata) -> OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    if !file.path.is_empty() {
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options
+}
+
+/// Creates the directories and links of the image. Regular files are created
+/// by the disk writer.
+pub fn prepare_tree(target: &Path, image: &ImageMetadata) -> Result<()> {
+    for file in image.files.iter() {
+        let path = target_path(target, file)?;
+        match &file.kind {
+            FileKind::Regular { .. } => {}
+            FileKind::Directory if !file.path.is_empty() && path.is_symlink() => {
+                bail!("{} already exists and is a link", path.display())
+            }
+            FileKind::Directory => fs::create_dir_all(&path)?,
+            FileKind::Symlink { target } => create_symlink(target, &path)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, path: &Path) -> Result<()> {
+    match fs::read_link(path) {
+        Ok(x) if x == Path::new(target) => return Ok(()),
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(_) => bail!("{} already exists and is not a link", path.display()),
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &str, path: &Path) -> Result<()> {
+    warn!(
+        "Skipping link {} (not supported on this platform)",
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+/// Applies permissions and modification times of the image to the tree, once
+/// all of its content has been written. The root of the image is left alone.
+pub fn finish_tree(target: &Path, image: &ImageMetadata) -> Result<()> {
+    // Children first, as writing into a directory changes its modification time.
+    for file in image.files.iter().skip(1).rev() {
+        if let FileKind::Symlink { .. } = file.kind {
+            continue;
+        }
+        let path = target_path(target, file)?;
+        let mtime = UNIX_EPOCH
+            + Duration::from_secs(file.mtime_secs.max(0) as u64)
+            + Duration::from_nanos(file.mtime_nanos as u64);
+        if let Err(e) =
+            File::open(&path).and_then(|x| x.set_times(FileTimes::new().set_modified(mtime)))
+        {
+            warn!(
+                "Unable to set modification time of {} ({})",
+                path.display(),
+                e
+            );
+        }
+        if let Err(e) = set_mode(&path, file.mode) {
+            warn!("Unable to set permissions of {} ({})", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: FileKind) -> FileMetadata {
+        FileMetadata {
+            path: path.to_owned(),
+            kind,
+            mode: 0o644,
+            mtime_secs: 0,
+            mtime_nanos: 0,
+        }
+    }
+
+    fn image(files: Vec<FileMetadata>) -> ImageMetadata {
+        ImageMetadata {
+            files: files.into_boxed_slice(),
+            chunks: Box::new([]),
+            fec: None,
+            chunking: crate::chunking::Chunking::Fixed { size: 1024 },
+            hash: crate::hash::HashAlgorithm::Xxh3,
+            digest: crate::hash::Digest::new(),
+        }
+    }
+
+    #[test]
+    fn paths_inside_target() {
+        let target = Path::new("/nonexistent/target");
+        let path = |path| target_path(target, &entry(path, FileKind::Directory));
+        assert_eq!(path("").unwrap(), target);
+        assert_eq!(path("a").unwrap(), target.join("a"));
+        assert_eq!(path("a/b.txt").unwrap(), target.join("a").join("b.txt"));
+        assert_eq!(path("a/..b/c.").unwrap(), target.join("a/..b/c."));
+    }
+
+    #[test]
+    fn paths_outside_target() {
+        let target = Path::new("/nonexistent/target");
+        for path in [
+            "..",
+            "a/..",
+            "../a",
+            "a/../../b",
+            ".",
+            "a/./b",
+            "/etc",
+            "/",
+            "a//b",
+            "a/",
+            "a/b/",
+        ] {
+            assert!(
+                target_path(target, &entry(path, FileKind::Directory)).is_err(),
+                "{:?} was accepted",
+                path
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_through_links() {
+        let target = std::env::temp_dir().join(format!("multicats-tree-{}", std::process::id()));
+        fs::create_dir_all(target.join("dir")).unwrap();
+        let _ = fs::remove_file(target.join("link"));
+        std::os::unix::fs::symlink("/etc", target.join("link")).unwrap();
+
+        let regular = FileKind::Regular { size: 0 };
+        let result = (
+            target_path(&target, &entry("dir/passwd", regular.clone())),
+            target_path(&target, &entry("link", regular.clone())),
+            target_path(&target, &entry("link/passwd", regular.clone())),
+            target_path(&target, &entry("link/a/b", regular)),
+        );
+        fs::remove_dir_all(&target).unwrap();
+
+        assert!(result.0.is_ok());
+        assert!(result.1.is_ok());
+        assert!(result.2.is_err());
+        assert!(result.3.is_err());
+    }
+
+    #[test]
+    fn entries_beneath_links() {
+        let link = || FileKind::Symlink {
+            target: "/etc".to_owned(),
+        };
+        let regular = || FileKind::Regular { size: 0 };
+        let tree = |files| check_tree(&image(files));
+
+        assert!(
+            tree(vec![
+                entry("", FileKind::Directory),
+                entry("a", link()),
+                entry("ab", FileKind::Directory),
+                entry("ab/passwd", regular()),
+            ])
+            .is_ok()
+        );
+        assert!(
+            tree(vec![
+                entry("", FileKind::Directory),
+                entry("a", link()),
+                entry("a/passwd", regular())
+            ])
+            .is_err()
+        );
+        assert!(
+            tree(vec![
+                entry("", FileKind::Directory),
+                entry("a", FileKind::Directory),
+                entry("a/b", link()),
+                entry("a/b/c/passwd", regular()),
+            ])
+            .is_err()
+        );
+        assert!(tree(vec![entry("", link()), entry("passwd", regular())]).is_err());
+    }
+}
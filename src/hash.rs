@@ -0,0 +1,67 @@
+use std::hash::Hasher as _;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use twox_hash::XxHash3_64;
+
+pub type Digest = heapless::Vec<u8, 32>;
+
+/// Hash function used for chunk hashes and the whole image digest.
+///
+/// XXH3 only protects against accidental corruption, the other two also
+/// protect against tampering when the image metadata is authenticated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn is_cryptographic(self) -> bool {
+        !matches!(self, HashAlgorithm::Xxh3)
+    }
+
+    pub fn hash(self, data: &[u8]) -> Digest {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(XxHash3_64::new())),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+pub enum Hasher {
+    Xxh3(Box<XxHash3_64>),
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Xxh3(x) => x.write(data),
+            Hasher::Blake3(x) => {
+                x.update(data);
+            }
+            Hasher::Sha256(x) => x.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        let out = match self {
+            Hasher::Xxh3(x) => Digest::from_slice(&x.finish().to_be_bytes()),
+            Hasher::Blake3(x) => Digest::from_slice(x.finalize().as_bytes()),
+            Hasher::Sha256(x) => Digest::from_slice(&x.finalize()),
+        };
+        out.expect("Digest does not fit its buffer")
+    }
+}
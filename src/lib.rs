@@ -1,41 +1,299 @@
+pub mod chunking;
+pub mod client;
+pub mod hash;
 pub mod net;
+pub mod server;
 
 use std::net::SocketAddr;
 
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use raptorq::ObjectTransmissionInformation;
 use serde::{Deserialize, Serialize};
+use twox_hash::XxHash3_64;
+
+use crate::{
+    chunking::Chunking,
+    hash::{Digest, HashAlgorithm},
+};
 
 pub trait Capacity {
     const CAPACITY: usize;
 }
 
-pub type ChunkRequest = heapless::Vec<usize, 40>;
+pub type FragmentRanges = heapless::Vec<FragmentRange, 40>;
 
 impl<T, const N: usize> Capacity for heapless::Vec<T, N> {
     const CAPACITY: usize = N;
 }
 
+/// Identifies the transfer a packet belongs to, so that packets of other
+/// servers sharing the same groups can be told apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SessionId {
+    /// Random identifier chosen by the server when it starts.
+    pub session: u64,
+    /// Content identifier of the image, see `ImageMetadata::content_id`. Zero
+    /// when streaming.
+    pub image: u64,
+}
+
+/// Random identifier chosen by a client when it starts, as several clients
+/// may share an address.
+pub type ClientId = u64;
+
+/// Reception statistics a client periodically sends to the server.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ReceptionReport {
+    /// Fraction of the packets sent by the server that were lost since the last report.
+    pub loss: f32,
+    /// Data received since the last report, in bits per second.
+    pub goodput: u64,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ClientMessage {
+    Request(FragmentRanges),
+    Report(ReceptionReport),
+    /// The client has received and verified the whole image.
+    Completed,
+}
+
+/// A packet sent by a client to the request socket of the server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClientPacket {
+    pub session: SessionId,
+    pub client: ClientId,
+    pub message: ClientMessage,
+}
+
+/// A range of bytes of a chunk that a client is missing.
+///
+/// When FEC is enabled `offset` is always zero and `len` is the amount of
+/// encoding symbols (in bytes) that the client still needs to decode the chunk.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct FragmentRange {
+    pub chunk: usize,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// A fragment of a chunk.
+///
+/// Without forward error correction `offset` is the position of `data` inside
+/// the chunk. When FEC is enabled `offset` carries the serialized RaptorQ
+/// payload identifier of the encoding symbol in `data` instead.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ChunkData<'a> {
+    pub session: SessionId,
+    /// Sequence number of the packet, used by clients to estimate loss.
+    pub seq: u64,
     pub chunk: usize,
     pub offset: usize,
     pub data: &'a [u8],
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FileKind {
+    Regular { size: u64 },
+    Directory,
+    Symlink { target: String },
+}
+
+/// An entry of the image manifest.
+///
+/// The first entry always describes the root of the image and has an empty
+/// path: a single regular file when a file is being transferred, or a
+/// directory containing all the other entries.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ChunkMetadata {
+pub struct FileMetadata {
+    /// Path relative to the root of the image, with `/` as separator.
+    pub path: String,
+    pub kind: FileKind,
+    pub mode: u32,
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+}
+
+/// A place of the image where the content of a chunk is found.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct ChunkLocation {
+    /// Index of the file in `ImageMetadata::files`.
+    pub file: usize,
+    /// Offset of the chunk inside the file.
     pub offset: u64,
+}
+
+/// A distinct chunk of content of the image, which may appear in several places.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChunkMetadata {
+    /// Every place where the chunk appears, the server reads it from the first.
+    pub locations: Vec<ChunkLocation>,
     pub size: usize,
-    pub hash: u64,
+    /// Size of the chunk once zstd compressed, `None` when it is sent as is.
+    pub compressed_size: Option<usize>,
+    /// The chunk only contains zeros. It is never transmitted and has no hash,
+    /// clients fill it in on their own.
+    pub zero: bool,
+    /// Hash of the uncompressed content of the chunk.
+    pub hash: Digest,
+}
+
+impl ChunkMetadata {
+    /// Amount of bytes of the chunk that are actually transferred.
+    pub fn wire_size(&self) -> usize {
+        self.compressed_size.unwrap_or(self.size)
+    }
+
+    /// Amount of bytes of the image covered by the chunk.
+    pub fn image_size(&self) -> u64 {
+        self.size as u64 * self.locations.len() as u64
+    }
+}
+
+/// Forward error correction scheme used for chunk fragments.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct FecParameters {
+    /// Size of each RaptorQ encoding symbol.
+    pub symbol_size: u16,
+    /// Repair symbols sent for each source block, as a percentage of its source symbols.
+    pub overhead: u32,
+}
+
+impl FecParameters {
+    pub fn transmission_info(&self, chunk_size: usize) -> ObjectTransmissionInformation {
+        ObjectTransmissionInformation::with_defaults(chunk_size as u64, self.symbol_size)
+    }
+
+    pub fn repair_symbols(&self, source_symbols: usize) -> u32 {
+        (source_symbols as u32 * self.overhead).div_ceil(100)
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ImageMetadata {
+    pub files: Box<[FileMetadata]>,
     pub chunks: Box<[ChunkMetadata]>,
+    pub fec: Option<FecParameters>,
+    pub chunking: Chunking,
+    /// Algorithm of the chunk hashes and of `digest`.
+    pub hash: HashAlgorithm,
+    /// Digest of the content of all the regular files of the image, in order.
+    pub digest: Digest,
+}
+
+impl ImageMetadata {
+    pub fn content_id(&self) -> u64 {
+        XxHash3_64::oneshot(
+            &postcard::to_allocvec(self).expect("Failed to serialize image metadata"),
+        )
+    }
+
+    pub fn size(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|file| match file.kind {
+                FileKind::Regular { size } => size,
+                _ => 0,
+            })
+            .sum()
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ServerDiscovery {
-    pub metadata_socket: SocketAddr,
+    pub session: SessionId,
+    /// Address serving the signed image metadata over TCP, if enabled.
+    pub metadata_socket: Option<SocketAddr>,
+    /// Multicast group on which the signed image metadata is carouselled, if enabled.
+    pub metadata_group: Option<SocketAddr>,
+    /// BLAKE3 hash of the signed image metadata, as sent by either.
+    pub metadata_digest: [u8; 32],
     pub request_socket: SocketAddr,
+    /// Multicast group to which clients send their requests instead of
+    /// `request_socket`, if enabled. Reports and completions still go to
+    /// `request_socket`.
+    pub request_group: Option<SocketAddr>,
     pub transfer_socket: SocketAddr,
+    /// The server streams its input instead of serving an image, its chunks
+    /// are described by `StreamAnnouncement`s on `metadata_group`.
+    pub stream: bool,
+}
+
+/// A block of the signed image metadata, sent on the metadata group.
+///
+/// Blocks are cut on a fixed grid and sent over and over, clients put them
+/// together in any order and check the result against `metadata_digest`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MetadataBlock<'a> {
+    pub session: SessionId,
+    /// Size of the whole signed metadata.
+    pub size: usize,
+    pub offset: usize,
+    pub data: &'a [u8],
+}
+
+/// Sent on the metadata group instead of `MetadataBlock`s when streaming,
+/// over and over for the chunks still available for retransmission.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamAnnouncement {
+    pub session: SessionId,
+    pub chunking: Chunking,
+    pub hash: HashAlgorithm,
+    pub fec: Option<FecParameters>,
+    /// Chunks before this one are no longer available.
+    pub window_start: usize,
+    /// Index of the first chunk of `chunks`.
+    pub first: usize,
+    /// The chunks of the stream, each with a single location in file zero.
+    pub chunks: Vec<ChunkMetadata>,
+    /// Set once the whole input has been read.
+    pub end: Option<StreamEnd>,
+}
+
+/// Largest stream window, so that clients can bound what an announcement
+/// makes them allocate.
+pub const MAX_STREAM_WINDOW: usize = 4096;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StreamEnd {
+    pub chunks: usize,
+    pub size: u64,
+    /// Digest of the whole stream.
+    pub digest: Digest,
+}
+
+/// Domain separation prefixes for the payloads the server signs.
+pub const DISCOVERY_SIGNATURE_CONTEXT: &[u8] = b"multicats server discovery\0";
+pub const METADATA_SIGNATURE_CONTEXT: &[u8] = b"multicats image metadata\0";
+pub const STREAM_SIGNATURE_CONTEXT: &[u8] = b"multicats stream announcement\0";
+
+/// A postcard encoded payload, signed with the server key if it has one.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Signed<'a> {
+    pub payload: &'a [u8],
+    pub signature: Option<Signature>,
+}
+
+impl<'a> Signed<'a> {
+    pub fn new(context: &[u8], payload: &'a [u8], key: Option<&SigningKey>) -> Signed<'a> {
+        Signed {
+            payload,
+            signature: key.map(|key| key.sign(&[context, payload].concat())),
+        }
+    }
+
+    /// Checks that the payload was signed by one of `keys`. Anything passes
+    /// when no keys are trusted.
+    pub fn verify(&self, context: &[u8], keys: &[VerifyingKey]) -> bool {
+        if keys.is_empty() {
+            return true;
+        }
+        let Some(signature) = &self.signature else {
+            return false;
+        };
+        let message = [context, self.payload].concat();
+        keys.iter()
+            .any(|key| key.verify(&message, signature).is_ok())
+    }
 }
@@ -1,6 +1,6 @@
 use anyhow::{Error, Result};
 use getifaddrs::{Address, Interface, InterfaceFlags, getifaddrs};
-use socket2::{InterfaceIndexOrAddress, SockRef};
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, SockRef, Socket, Type};
 use std::{
     collections::BTreeSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
@@ -137,7 +137,17 @@ pub async fn new_receiver_multicast_socket(
     #[cfg(not(target_os = "windows"))]
     let sockaddr = group;
 
-    let socket = UdpSocket::bind(sockaddr).await?;
+    // Several processes of the same host may listen to a group, such as a
+    // client and a server both joining the request group.
+    let socket = Socket::new(
+        Domain::for_address(sockaddr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&sockaddr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
 
     match group.ip() {
         IpAddr::V4(ipv4) => match interface {
@@ -0,0 +1,731 @@
+mod cache;
+mod image;
+mod schedule;
+mod stream;
+mod tasks;
+
+use std::{
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicU32},
+    time::Duration,
+};
+
+use anyhow::{Error, Result};
+use ed25519_dalek::SigningKey;
+use log::info;
+use socket2::InterfaceIndexOrAddress;
+use tokio::{
+    sync::{SetOnce, watch},
+    try_join,
+};
+use tokio_util::sync::CancellationToken;
+
+pub use schedule::{DemandScheduler, RoundRobinScheduler, Scheduler, Scheduling};
+
+use crate::{
+    FecParameters, FileKind, FileMetadata, ImageMetadata, MAX_STREAM_WINDOW,
+    METADATA_SIGNATURE_CONTEXT, SessionId, Signed,
+    chunking::Chunking,
+    hash::{Digest, HashAlgorithm},
+    net::{NetworkInterface, get_interface},
+};
+
+/// Counterpart of the default metadata group for IPv4 discovery sockets.
+const IPV4_METADATA_GROUP: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 3), 7892));
+
+/// Options of a [`Server`], see [`ServerBuilder`] for their meaning.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub discovery_socket: SocketAddr,
+    pub transfer_socket: SocketAddr,
+    pub metadata_group: Option<SocketAddr>,
+    pub request_group: Option<SocketAddr>,
+    pub metadata_rate: u32,
+    pub tcp_metadata: bool,
+    pub unicast_address: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub max_hops: u32,
+    pub discovery_interval: Duration,
+    pub chunk_size: usize,
+    pub content_defined_chunking: bool,
+    pub max_udp_payload_size: u16,
+    pub flood_speed: u32,
+    pub min_flood_speed: Option<u32>,
+    pub target_loss: f32,
+    pub drop_slow_after: Option<Duration>,
+    pub expect_clients: Option<usize>,
+    pub idle_timeout: Option<Duration>,
+    pub fec_overhead: Option<u32>,
+    pub compression_level: Option<i32>,
+    pub metadata_file: Option<PathBuf>,
+    pub metadata_cache: bool,
+    pub hash_threads: Option<usize>,
+    pub direct_io: bool,
+    pub stream_window: usize,
+    pub carousel: bool,
+    pub scheduling: Scheduling,
+    pub signing_key: Option<SigningKey>,
+    pub hash: HashAlgorithm,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            discovery_socket: SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0xff18, 0, 0, 0, 0, 0, 0, 1),
+                7890,
+                0,
+                0,
+            )),
+            transfer_socket: SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0xff18, 0, 0, 0, 0, 0, 0, 2),
+                7891,
+                0,
+                0,
+            )),
+            metadata_group: Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0xff18, 0, 0, 0, 0, 0, 0, 3),
+                7892,
+                0,
+                0,
+            ))),
+            request_group: None,
+            metadata_rate: 16 * 1024 * 1024,
+            tcp_metadata: true,
+            unicast_address: None,
+            interface: None,
+            max_hops: 1,
+            discovery_interval: Duration::from_millis(1000),
+            chunk_size: 5 * 1024 * 1024,
+            content_defined_chunking: false,
+            max_udp_payload_size: 1500 - 40 - 8,
+            flood_speed: 1024 * 1024 * 1024,
+            min_flood_speed: None,
+            target_loss: 0.01,
+            drop_slow_after: None,
+            expect_clients: None,
+            idle_timeout: None,
+            fec_overhead: None,
+            compression_level: None,
+            metadata_file: None,
+            metadata_cache: true,
+            hash_threads: None,
+            direct_io: false,
+            stream_window: 16,
+            carousel: false,
+            scheduling: Scheduling::default(),
+            signing_key: None,
+            hash: HashAlgorithm::default(),
+        }
+    }
+}
+
+/// Snapshot of the state of a running [`Server`].
+#[derive(Clone, Debug, Default)]
+pub struct ServerProgress {
+    /// Bytes of chunk data sent on the transfer group.
+    pub bytes_sent: u64,
+    /// Current sending rate in bits per second.
+    pub rate: u32,
+    /// Number of clients that reported having completed the transfer.
+    pub completed_clients: usize,
+    /// Times every chunk was queued in carousel mode.
+    pub carousel_rounds: u64,
+}
+
+struct ServerState {
+    token: CancellationToken,
+    unicast: IpAddr,
+    interface: NetworkInterface,
+    interface_id: InterfaceIndexOrAddress,
+    metadata_socket: SetOnce<SocketAddr>,
+    request_socket: SetOnce<SocketAddr>,
+    session: SessionId,
+    rate: AtomicU32,
+    progress: watch::Sender<ServerProgress>,
+    image: ImageMetadata,
+    /// The image metadata, postcard encoded and signed.
+    signed_metadata: Box<[u8]>,
+    sources: Box<[PathBuf]>,
+    /// Set when streaming, `image` then only describes how chunks are cut,
+    /// hashed and sent.
+    stream: Option<stream::StreamState>,
+    config: ServerConfig,
+}
+
+impl ServerState {
+    /// Size on the wire of a chunk that can be sent. `None` for chunks that
+    /// are never sent and, when streaming, for chunks not in the window.
+    fn wire_size(&self, chunk: usize) -> Option<usize> {
+        match &self.stream {
+            Some(stream) => stream.wire_size(chunk),
+            None => self
+                .image
+                .chunks
+                .get(chunk)
+                .filter(|chunk| !chunk.zero)
+                .map(|chunk| chunk.wire_size()),
+        }
+    }
+}
+
+enum Input {
+    Path(PathBuf),
+    Stream(Box<dyn Read + Send>),
+}
+
+pub struct ServerBuilder {
+    input: Input,
+    scheduler: Option<Box<dyn Scheduler>>,
+    config: ServerConfig,
+}
+
+impl ServerBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> ServerBuilder {
+        ServerBuilder {
+            input: Input::Path(path.into()),
+            scheduler: None,
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// Streams `source` as it is read instead of serving an image, see
+    /// [`Server::stream_builder`].
+    pub fn stream(source: impl Read + Send + 'static) -> ServerBuilder {
+        ServerBuilder {
+            input: Input::Stream(Box::new(source)),
+            scheduler: None,
+            config: ServerConfig::default(),
+        }
+    }
+
+    /// Replaces all the options at once.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Multicast group on which the server announces itself.
+    pub fn discovery_socket(mut self, discovery_socket: SocketAddr) -> Self {
+        self.config.discovery_socket = discovery_socket;
+        self
+    }
+
+    /// Multicast group on which chunk data is sent.
+    pub fn transfer_socket(mut self, transfer_socket: SocketAddr) -> Self {
+        self.config.transfer_socket = transfer_socket;
+        self
+    }
+
+    /// Multicast group on which the image metadata is carouselled, `None`
+    /// to only serve it over TCP. The default group is replaced with
+    /// 239.255.0.3:7892 when the discovery socket is IPv4.
+    pub fn metadata_group(mut self, metadata_group: Option<SocketAddr>) -> Self {
+        self.config.metadata_group = metadata_group;
+        self
+    }
+
+    /// Multicast group to which clients send their requests, so that they
+    /// hear each other and skip requests another client just sent. `None`
+    /// to have them sent to the request socket only.
+    pub fn request_group(mut self, request_group: Option<SocketAddr>) -> Self {
+        self.config.request_group = request_group;
+        self
+    }
+
+    /// Rate of the metadata carousel in bits per second.
+    pub fn metadata_rate(mut self, metadata_rate: u32) -> Self {
+        self.config.metadata_rate = metadata_rate;
+        self
+    }
+
+    /// Also serve the image metadata over TCP, for clients that cannot
+    /// receive the carousel.
+    pub fn tcp_metadata(mut self, tcp_metadata: bool) -> Self {
+        self.config.tcp_metadata = tcp_metadata;
+        self
+    }
+
+    /// Address on which the metadata and request sockets are bound,
+    /// defaults to one of the addresses of the interface.
+    pub fn unicast_address(mut self, unicast_address: Option<IpAddr>) -> Self {
+        self.config.unicast_address = unicast_address;
+        self
+    }
+
+    /// Name or index of the network interface, defaults to the first
+    /// multicast capable one.
+    pub fn interface(mut self, interface: Option<String>) -> Self {
+        self.config.interface = interface;
+        self
+    }
+
+    /// Hop limit of multicast packets.
+    pub fn max_hops(mut self, max_hops: u32) -> Self {
+        self.config.max_hops = max_hops;
+        self
+    }
+
+    /// Time between discovery announcements.
+    pub fn discovery_interval(mut self, discovery_interval: Duration) -> Self {
+        self.config.discovery_interval = discovery_interval;
+        self
+    }
+
+    /// Size of the chunks the image is split into.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.config.chunk_size = chunk_size;
+        self
+    }
+
+    /// Cut chunks at content-defined boundaries, with `chunk_size` as the
+    /// largest size, so that clients can find shifted content in their seeds.
+    pub fn content_defined_chunking(mut self, content_defined_chunking: bool) -> Self {
+        self.config.content_defined_chunking = content_defined_chunking;
+        self
+    }
+
+    /// Largest datagram the server sends.
+    pub fn max_udp_payload_size(mut self, max_udp_payload_size: u16) -> Self {
+        self.config.max_udp_payload_size = max_udp_payload_size;
+        self
+    }
+
+    /// Sending rate in bits per second, or the highest one with adaptive
+    /// rate control.
+    pub fn flood_speed(mut self, flood_speed: u32) -> Self {
+        self.config.flood_speed = flood_speed;
+        self
+    }
+
+    /// Enables adaptive rate control, never going below this rate.
+    pub fn min_flood_speed(mut self, min_flood_speed: Option<u32>) -> Self {
+        self.config.min_flood_speed = min_flood_speed;
+        self
+    }
+
+    /// Loss above which adaptive rate control slows down.
+    pub fn target_loss(mut self, target_loss: f32) -> Self {
+        self.config.target_loss = target_loss;
+        self
+    }
+
+    /// Ignore receivers that cannot keep up with the minimum rate for this long.
+    pub fn drop_slow_after(mut self, drop_slow_after: Option<Duration>) -> Self {
+        self.config.drop_slow_after = drop_slow_after;
+        self
+    }
+
+    /// Stop once this many clients completed the transfer.
+    pub fn expect_clients(mut self, expect_clients: Option<usize>) -> Self {
+        self.config.expect_clients = expect_clients;
+        self
+    }
+
+    /// Stop after receiving no requests for this long.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Enables forward error correction with this percentage of repair symbols.
+    pub fn fec_overhead(mut self, fec_overhead: Option<u32>) -> Self {
+        self.config.fec_overhead = fec_overhead;
+        self
+    }
+
+    /// Compresses chunks with zstd at this level before sending them. Chunks
+    /// that do not shrink are sent uncompressed.
+    pub fn compression_level(mut self, compression_level: Option<i32>) -> Self {
+        self.config.compression_level = compression_level;
+        self
+    }
+
+    /// Loads the image metadata from this file instead of computing it, or
+    /// writes it there once computed if the file does not exist yet or the
+    /// image changed since it was written.
+    pub fn metadata_file(mut self, metadata_file: Option<PathBuf>) -> Self {
+        self.config.metadata_file = metadata_file;
+        self
+    }
+
+    /// Caches the image metadata in a file next to the image, so that it is
+    /// only computed again when the image or the options change.
+    pub fn metadata_cache(mut self, metadata_cache: bool) -> Self {
+        self.config.metadata_cache = metadata_cache;
+        self
+    }
+
+    /// Threads hashing the image, defaults to one per CPU.
+    pub fn hash_threads(mut self, hash_threads: Option<usize>) -> Self {
+        self.config.hash_threads = hash_threads;
+        self
+    }
+
+    /// Bypasses the page cache when reading the image to hash it. Faster on
+    /// fast storage, but chunks are no longer cached when sent afterwards.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.config.direct_io = direct_io;
+        self
+    }
+
+    /// Chunks of a stream kept in memory for retransmission, at most
+    /// [`MAX_STREAM_WINDOW`]. Reading the input stalls until the oldest of
+    /// them was sent and no client asked for it again for a while.
+    pub fn stream_window(mut self, stream_window: usize) -> Self {
+        self.config.stream_window = stream_window;
+        self
+    }
+
+    /// Sends every chunk over and over instead of answering requests, for
+    /// clients that cannot reach the server, such as behind a data diode.
+    pub fn carousel(mut self, carousel: bool) -> Self {
+        self.config.carousel = carousel;
+        self
+    }
+
+    /// Order in which requested chunks are sent.
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.config.scheduling = scheduling;
+        self
+    }
+
+    /// Replaces the built-in scheduling policies with a custom one.
+    pub fn scheduler(mut self, scheduler: impl Scheduler + 'static) -> Self {
+        self.scheduler = Some(Box::new(scheduler));
+        self
+    }
+
+    /// Key used to sign announcements and metadata. Requires a cryptographic
+    /// `hash`, which chunks are authenticated with.
+    pub fn signing_key(mut self, signing_key: Option<SigningKey>) -> Self {
+        self.config.signing_key = signing_key;
+        self
+    }
+
+    /// Algorithm used for chunk hashes and the image digest.
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.config.hash = hash;
+        self
+    }
+
+    /// Validates the options and computes the image metadata, which requires
+    /// reading the whole image.
+    pub fn build(self) -> Result<Server> {
+        let mut config = self.config;
+
+        if config.discovery_socket.is_ipv4()
+            && config.metadata_group == ServerConfig::default().metadata_group
+        {
+            config.metadata_group = Some(IPV4_METADATA_GROUP);
+        }
+
+        if config.discovery_socket.is_ipv6() != config.transfer_socket.is_ipv6() {
+            return Err(Error::msg(
+                "Discovery and transfer sockets must be of the same family.",
+            ));
+        }
+
+        if !config.discovery_socket.ip().is_multicast()
+            || !config.transfer_socket.ip().is_multicast()
+        {
+            return Err(Error::msg(
+                "Discovery and transfer addresses must be multicast groups.",
+            ));
+        }
+
+        match config.metadata_group {
+            Some(group) if group.is_ipv6() != config.discovery_socket.is_ipv6() => {
+                return Err(Error::msg(
+                    "Metadata group must be of the same family as the discovery socket.",
+                ));
+            }
+            Some(group) if !group.ip().is_multicast() => {
+                return Err(Error::msg("Metadata address must be a multicast group."));
+            }
+            Some(_) if config.metadata_rate == 0 => {
+                return Err(Error::msg("Metadata rate must be positive."));
+            }
+            None if !config.tcp_metadata => {
+                return Err(Error::msg(
+                    "Image metadata must be served either over multicast or over TCP.",
+                ));
+            }
+            _ => {}
+        }
+
+        match config.request_group {
+            Some(group) if group.is_ipv6() != config.discovery_socket.is_ipv6() => {
+                return Err(Error::msg(
+                    "Request group must be of the same family as the discovery socket.",
+                ));
+            }
+            Some(group) if !group.ip().is_multicast() => {
+                return Err(Error::msg("Request address must be a multicast group."));
+            }
+            _ => {}
+        }
+
+        if config.carousel {
+            if config.metadata_group.is_none() {
+                return Err(Error::msg(
+                    "Carousel mode requires the metadata carousel, clients may not reach the server.",
+                ));
+            }
+            if config.idle_timeout.is_some() {
+                return Err(Error::msg(
+                    "Idle timeout cannot be used in carousel mode, which ignores requests.",
+                ));
+            }
+            if matches!(self.input, Input::Stream(_)) {
+                return Err(Error::msg("Streams cannot be sent in carousel mode."));
+            }
+        }
+
+        if config.flood_speed == 0 || config.min_flood_speed == Some(0) {
+            return Err(Error::msg("Flood speed must be positive."));
+        }
+
+        if let Some(floor) = config.min_flood_speed
+            && floor > config.flood_speed
+        {
+            return Err(Error::msg(
+                "Minimum flood speed cannot be greater than the flood speed.",
+            ));
+        }
+
+        let Some(interface) = get_interface(config.interface.as_deref())? else {
+            return Err(Error::msg("Cannot find requested interface."));
+        };
+
+        let interface_id = if config.discovery_socket.is_ipv6() {
+            InterfaceIndexOrAddress::Index(interface.index)
+        } else {
+            let address = interface
+                .ips
+                .iter()
+                .filter_map(|ip| match ip {
+                    IpAddr::V4(ip) => Some(ip),
+                    _ => None,
+                })
+                .next();
+            if let Some(&address) = address {
+                InterfaceIndexOrAddress::Address(address)
+            } else {
+                return Err(Error::msg(
+                    "In IPv4 mode the selected interface needs to have at least one IPv4 address assigned to it.",
+                ));
+            }
+        };
+
+        let unicast = match config.unicast_address {
+            Some(ip) => {
+                if ip.is_ipv6() != config.discovery_socket.is_ipv6() {
+                    return Err(Error::msg(
+                        "Unicast address must be of the same family as the multicast groups.",
+                    ));
+                }
+                ip
+            }
+            None => {
+                let Some(&x) = interface
+                    .ips
+                    .iter()
+                    .find(|&ip| ip.is_ipv6() == config.discovery_socket.is_ipv6())
+                else {
+                    return Err(Error::msg(
+                        "Cannot find any suitable unicast address on the selected interface.",
+                    ));
+                };
+                x
+            }
+        };
+
+        if config.signing_key.is_some() && !config.hash.is_cryptographic() {
+            return Err(Error::msg(
+                "Signed images must be hashed with a cryptographic hash, chunks could be forged otherwise.",
+            ));
+        }
+
+        if let Some(key) = &config.signing_key {
+            info!(
+                "Signing announcements with public key {}",
+                hex::encode(key.verifying_key().as_bytes())
+            );
+        }
+
+        let chunking = if config.content_defined_chunking {
+            Chunking::content_defined(config.chunk_size)
+        } else {
+            Chunking::Fixed {
+                size: config.chunk_size,
+            }
+        };
+
+        let (mut image, sources, input) = match self.input {
+            Input::Path(path) => {
+                let (files, sources) = image::walk_tree(&path)?;
+                let (image, compression_level) =
+                    cache::image_metadata(&path, files, &sources, chunking, &config)?;
+                image::check_compression(&image, &sources, compression_level)?;
+                config.compression_level = compression_level;
+                (image, sources, None)
+            }
+            Input::Stream(source) => {
+                if config.metadata_group.is_none() {
+                    return Err(Error::msg(
+                        "Streaming requires the metadata carousel, which announces the chunks.",
+                    ));
+                }
+                if config.stream_window == 0 {
+                    return Err(Error::msg("Stream window cannot be empty."));
+                }
+                if config.stream_window > MAX_STREAM_WINDOW {
+                    return Err(Error::msg(format!(
+                        "Stream window cannot exceed {} chunks.",
+                        MAX_STREAM_WINDOW
+                    )));
+                }
+                let image = ImageMetadata {
+                    files: Box::new([FileMetadata {
+                        path: String::new(),
+                        kind: FileKind::Regular { size: 0 },
+                        mode: 0o644,
+                        mtime_secs: 0,
+                        mtime_nanos: 0,
+                    }]),
+                    chunks: Box::new([]),
+                    fec: None,
+                    chunking,
+                    hash: config.hash,
+                    digest: Digest::new(),
+                };
+                (image, Vec::new(), Some(source))
+            }
+        };
+        // Metadata files keep the hash they were computed with.
+        if config.signing_key.is_some() && !image.hash.is_cryptographic() {
+            return Err(Error::msg(
+                "Signed images must be hashed with a cryptographic hash, the image metadata was not.",
+            ));
+        }
+        image.fec = config.fec_overhead.map(|overhead| FecParameters {
+            symbol_size: tasks::max_fragment_size(config.max_udp_payload_size) as u16,
+            overhead,
+        });
+
+        let session = SessionId {
+            session: getrandom::u64()?,
+            image: if input.is_some() {
+                0
+            } else {
+                image.content_id()
+            },
+        };
+
+        let scheduler = self
+            .scheduler
+            .unwrap_or_else(|| config.scheduling.scheduler());
+
+        let signed_metadata = postcard::to_allocvec(&Signed::new(
+            METADATA_SIGNATURE_CONTEXT,
+            &postcard::to_allocvec(&image)?,
+            config.signing_key.as_ref(),
+        ))?;
+
+        let rate = config.min_flood_speed.unwrap_or(config.flood_speed);
+
+        Ok(Server {
+            state: Arc::new(ServerState {
+                token: CancellationToken::new(),
+                unicast,
+                interface_id,
+                interface,
+                metadata_socket: SetOnce::new(),
+                request_socket: SetOnce::new(),
+                session,
+                rate: AtomicU32::new(rate),
+                progress: watch::Sender::new(ServerProgress {
+                    rate,
+                    ..Default::default()
+                }),
+                image,
+                signed_metadata: signed_metadata.into_boxed_slice(),
+                sources: sources.into_boxed_slice(),
+                stream: input
+                    .is_some()
+                    .then(|| stream::StreamState::new(config.expect_clients)),
+                config,
+            }),
+            input,
+            scheduler,
+        })
+    }
+}
+
+/// Serves an image to any number of clients over multicast.
+pub struct Server {
+    state: Arc<ServerState>,
+    /// The input to stream, taken when running.
+    input: Option<Box<dyn Read + Send>>,
+    scheduler: Box<dyn Scheduler>,
+}
+
+impl Server {
+    pub fn builder(path: impl Into<PathBuf>) -> ServerBuilder {
+        ServerBuilder::new(path)
+    }
+
+    /// Streams `source`, such as a pipe, to clients while reading it. Chunks
+    /// are announced as they are read and can only be retransmitted while
+    /// they are in the window, clients that fall behind cannot complete.
+    /// Sending starts once the expected clients, or a first one, joined.
+    pub fn stream_builder(source: impl Read + Send + 'static) -> ServerBuilder {
+        ServerBuilder::stream(source)
+    }
+
+    /// Metadata of the image. When streaming it has no chunks and only
+    /// describes how they are cut, hashed and sent.
+    pub fn image(&self) -> &ImageMetadata {
+        &self.state.image
+    }
+
+    /// Token that stops the server when cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.state.token.clone()
+    }
+
+    pub fn progress(&self) -> watch::Receiver<ServerProgress> {
+        self.state.progress.subscribe()
+    }
+
+    /// Serves the image until the server is cancelled, or until the conditions
+    /// to stop set with `expect_clients` and `idle_timeout` are met.
+    pub async fn run(self) -> Result<()> {
+        let state = self.state;
+
+        let discovery_task = tasks::spawn(tasks::server_discovery(state.clone()));
+        let metadata_task = tasks::spawn(tasks::metadata_server(state.clone()));
+        let carousel_task = tasks::spawn(tasks::metadata_carousel(state.clone()));
+        let transfer_task =
+            tasks::spawn(tasks::chunk_request_server(state.clone(), self.scheduler));
+        let stream_task = tasks::spawn(stream::read_stream(state.clone(), self.input));
+
+        let result = try_join!(
+            discovery_task,
+            metadata_task,
+            carousel_task,
+            transfer_task,
+            stream_task
+        );
+        if result.is_err() {
+            // The tasks that did not fail would keep serving otherwise.
+            state.token.cancel();
+        }
+
+        result.map(|_| ())
+    }
+}
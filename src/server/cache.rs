@@ -0,0 +1,176 @@
+use std::{
+    ffi::OsString,
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash3_64;
+
+use super::{ServerConfig, image::compute_image_metadata};
+use crate::{FileMetadata, ImageMetadata, chunking::Chunking};
+
+/// Image metadata as stored on disk, together with what is needed to tell
+/// whether it still describes the image.
+#[derive(Deserialize, Serialize)]
+struct StoredMetadata {
+    /// Identifies the state of the source files and the options the metadata
+    /// was computed with, see `cache_key`.
+    key: u64,
+    /// Identifies the state of the source files alone, see `sources_key`.
+    sources: u64,
+    /// Needed to compress chunks again exactly as when they were measured.
+    compression_level: Option<i32>,
+    image: ImageMetadata,
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> u64 {
+    0
+}
+
+/// Sidecar file next to the root of the image, e.g. `disk.img.multicats`.
+/// There is none for `/`, as it would end up inside of the image.
+fn sidecar_path(root: &Path) -> Option<PathBuf> {
+    let root = fs::canonicalize(root).ok()?;
+    let mut name = OsString::from(root.file_name()?);
+    name.push(".multicats");
+    Some(root.with_file_name(name))
+}
+
+/// Changes whenever a source file is replaced, resized or modified.
+fn sources_key(sources: &[PathBuf]) -> io::Result<u64> {
+    let mut files = Vec::with_capacity(sources.len());
+    for source in sources {
+        let metadata = fs::metadata(source).or_else(|_| fs::symlink_metadata(source))?;
+        files.push((metadata.len(), metadata.modified().ok(), inode(&metadata)));
+    }
+    let key = postcard::to_allocvec(&files).expect("Failed to serialize source files key");
+    Ok(XxHash3_64::oneshot(&key))
+}
+
+fn cache_key(
+    root: &Path,
+    sources_key: u64,
+    chunking: Chunking,
+    config: &ServerConfig,
+) -> io::Result<u64> {
+    let key = postcard::to_allocvec(&(
+        fs::canonicalize(root)?,
+        sources_key,
+        chunking,
+        config.hash,
+        config.compression_level,
+    ))
+    .expect("Failed to serialize metadata cache key");
+    Ok(XxHash3_64::oneshot(&key))
+}
+
+fn load(path: &Path) -> Option<StoredMetadata> {
+    let data = fs::read(path).ok()?;
+    postcard::from_bytes(&data).ok()
+}
+
+fn store(path: &Path, stored: &StoredMetadata) -> io::Result<()> {
+    let data = postcard::to_allocvec(stored).expect("Failed to serialize image metadata");
+    // Written aside and renamed, so that an interrupted write is never loaded.
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+/// Whether `image` describes the same tree as `files`, ignoring modification
+/// times and permissions.
+fn same_tree(image: &ImageMetadata, files: &[FileMetadata]) -> bool {
+    image.files.len() == files.len()
+        && image
+            .files
+            .iter()
+            .zip(files)
+            .all(|(a, b)| a.path == b.path && a.kind == b.kind)
+}
+
+/// Returns the metadata of the image and the compression level its chunks
+/// were measured with.
+///
+/// An explicit metadata file is used as long as the source files did not
+/// change since it was written and it describes a tree with the same files.
+/// It is computed and written there if missing or outdated.
+/// Otherwise the sidecar cache is only used if the source files and options
+/// did not change since it was written. Either way the current permissions
+/// and modification times of the files are used.
+pub fn image_metadata(
+    root: &Path,
+    files: Vec<FileMetadata>,
+    sources: &[PathBuf],
+    chunking: Chunking,
+    config: &ServerConfig,
+) -> Result<(ImageMetadata, Option<i32>)> {
+    let sources_key = sources_key(sources)?;
+    let key = cache_key(root, sources_key, chunking, config)?;
+
+    let path = match &config.metadata_file {
+        Some(path) => {
+            match load(path) {
+                Some(stored) if stored.sources != sources_key => warn!(
+                    "Source files changed since {} was written, computing the image metadata again",
+                    path.display()
+                ),
+                Some(mut stored) => {
+                    if !same_tree(&stored.image, &files) {
+                        bail!("Metadata file {} does not match the image", path.display());
+                    }
+                    info!("Loaded image metadata from {}", path.display());
+                    stored.image.files = files.into_boxed_slice();
+                    return Ok((stored.image, stored.compression_level));
+                }
+                None => {}
+            }
+            Some(path.clone())
+        }
+        None if config.metadata_cache => {
+            let path = sidecar_path(root);
+            if let Some(path) = &path
+                && let Some(mut stored) = load(path)
+                && stored.key == key
+                && same_tree(&stored.image, &files)
+            {
+                info!("Loaded cached image metadata from {}", path.display());
+                stored.image.files = files.into_boxed_slice();
+                return Ok((stored.image, stored.compression_level));
+            }
+            path
+        }
+        None => None,
+    };
+
+    let stored = StoredMetadata {
+        key,
+        sources: sources_key,
+        compression_level: config.compression_level,
+        image: compute_image_metadata(files, sources, chunking, config)?,
+    };
+
+    if let Some(path) = path {
+        match store(&path, &stored) {
+            Ok(()) => info!("Saved image metadata to {}", path.display()),
+            Err(e) => warn!(
+                "Unable to save image metadata to {} ({})",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    Ok((stored.image, stored.compression_level))
+}
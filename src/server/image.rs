@@ -0,0 +1,539 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
+    fs::{self, File, Metadata},
+    io::{Error, ErrorKind, Read, Result, Seek, SeekFrom},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use zstd::bulk::Compressor;
+
+use super::ServerConfig;
+use crate::{
+    ChunkLocation, ChunkMetadata, FileKind, FileMetadata, ImageMetadata,
+    chunking::{Chunking, page_size},
+    hash::{Digest, HashAlgorithm},
+};
+
+#[cfg(unix)]
+fn file_mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+fn file_metadata(path: String, kind: FileKind, metadata: &Metadata) -> FileMetadata {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    FileMetadata {
+        path,
+        kind,
+        mode: file_mode(metadata),
+        mtime_secs: mtime.as_secs() as i64,
+        mtime_nanos: mtime.subsec_nanos(),
+    }
+}
+
+/// Walks `root` and lists every entry of the image together with its source
+/// path. Directories always precede their content.
+pub fn walk_tree(root: &Path) -> Result<(Vec<FileMetadata>, Vec<PathBuf>)> {
+    let mut files = Vec::new();
+    let mut sources = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+
+    while let Some((source, path)) = stack.pop() {
+        // The root itself is followed if it is a link.
+        let metadata = if path.is_empty() {
+            fs::metadata(&source)?
+        } else {
+            fs::symlink_metadata(&source)?
+        };
+        let kind = if metadata.is_symlink() {
+            let Some(target) = fs::read_link(&source)?.to_str().map(str::to_owned) else {
+                warn!(
+                    "Skipping {} (link target is not valid UTF-8)",
+                    source.display()
+                );
+                continue;
+            };
+            FileKind::Symlink { target }
+        } else if metadata.is_dir() {
+            let mut entries = fs::read_dir(&source)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<Result<Vec<_>>>()?;
+            // Reversed so that entries are popped from the stack in order.
+            entries.sort_unstable_by(|a, b| b.cmp(a));
+            for name in entries {
+                let Some(name) = name.to_str() else {
+                    warn!("Skipping {:?} (file name is not valid UTF-8)", name);
+                    continue;
+                };
+                let child = if path.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}/{}", path, name)
+                };
+                stack.push((source.join(name), child));
+            }
+            FileKind::Directory
+        } else if metadata.is_file() || path.is_empty() {
+            FileKind::Regular {
+                size: metadata.len(),
+            }
+        } else {
+            warn!("Skipping {} (unsupported file type)", source.display());
+            continue;
+        };
+
+        files.push(file_metadata(path, kind, &metadata));
+        sources.push(source);
+    }
+
+    if files.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Nothing to transfer"));
+    }
+
+    Ok((files, sources))
+}
+
+/// Chunks of distinct sizes checked at most by `check_compression`.
+const COMPRESSION_CHECKS: usize = 8;
+
+/// Reads the content of `chunk` from the first place it appears in.
+pub fn read_chunk(sources: &[PathBuf], chunk: &ChunkMetadata) -> Result<Vec<u8>> {
+    let location = chunk.locations[0];
+    let mut file = File::open(&sources[location.file])?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut data = vec![0u8; chunk.size];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Checks that chunks still compress to the sizes recorded in the metadata,
+/// which may have been computed with another version of zstd. Chunks are
+/// compressed again whenever they are sent, and the transfer fails on one
+/// that no longer matches. One chunk of each size is tried beforehand, since
+/// compression depends on it, to fail early in the common case.
+pub fn check_compression(
+    image: &ImageMetadata,
+    sources: &[PathBuf],
+    compression_level: Option<i32>,
+) -> Result<()> {
+    let mut compressor = compression_level.map(Compressor::new).transpose()?;
+    let mut sizes = HashSet::new();
+
+    for chunk in image.chunks.iter() {
+        let Some(compressed_size) = chunk.compressed_size else {
+            continue;
+        };
+        if sizes.len() >= COMPRESSION_CHECKS {
+            break;
+        }
+        if !sizes.insert(chunk.size) {
+            continue;
+        }
+        let Some(compressor) = &mut compressor else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Image metadata lists compressed chunks without a compression level",
+            ));
+        };
+        if compressor.compress(&read_chunk(sources, chunk)?)?.len() != compressed_size {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Chunks do not compress to the sizes recorded in the image metadata, it was likely computed with another version of zstd",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// A chunk read from the image, on its way to a hashing thread.
+struct Job {
+    seq: usize,
+    location: ChunkLocation,
+    data: Vec<u8>,
+}
+
+/// A chunk once hashed, and compressed if enabled.
+struct Hashed {
+    location: ChunkLocation,
+    data: Vec<u8>,
+    zero: bool,
+    hash: Digest,
+    compressed_size: Option<usize>,
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(path: &Path) -> Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_path: &Path) -> Result<File> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+/// Opens a source file, bypassing the page cache if `direct` is set and the
+/// file system allows it.
+fn open_source(path: &Path, size: u64, direct: bool) -> Result<Box<dyn Read>> {
+    if direct {
+        match open_direct(path) {
+            // Not limited to `size`, direct reads must span whole blocks.
+            Ok(file) => return Ok(Box::new(file)),
+            Err(e) => warn!(
+                "Unable to bypass the page cache of {} ({})",
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(Box::new(File::open(path)?.take(size)))
+}
+
+/// Cuts the regular files of the image into chunks, handing them over to the
+/// hashing threads in buffers taken from `pool`.
+fn read_chunks(
+    files: &[FileMetadata],
+    sources: &[PathBuf],
+    chunking: Chunking,
+    direct: bool,
+    pool: Receiver<Vec<u8>>,
+    jobs: SyncSender<Job>,
+) -> Result<()> {
+    let mut seq = 0;
+
+    for (index, (file, source)) in files.iter().zip(sources.iter()).enumerate() {
+        let FileKind::Regular { size: file_size } = file.kind else {
+            continue;
+        };
+
+        let mut file_done = 0u64;
+        let mut stopped = false;
+
+        chunking.split(open_source(source, file_size, direct)?, |pos, data| {
+            // Whatever was appended to the file since it was listed is left out.
+            if pos >= file_size {
+                return Ok(ControlFlow::Break(()));
+            }
+            let data = &data[..data.len().min((file_size - pos) as usize)];
+            // The other end only hangs up when hashing failed.
+            let Ok(mut buf) = pool.recv() else {
+                stopped = true;
+                return Ok(ControlFlow::Break(()));
+            };
+            buf.clear();
+            buf.extend_from_slice(data);
+            let job = Job {
+                seq,
+                location: ChunkLocation {
+                    file: index,
+                    offset: pos,
+                },
+                data: buf,
+            };
+            if jobs.send(job).is_err() {
+                stopped = true;
+                return Ok(ControlFlow::Break(()));
+            }
+            seq += 1;
+            file_done += data.len() as u64;
+            Ok(if file_done < file_size {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            })
+        })?;
+
+        if stopped {
+            return Ok(());
+        }
+
+        if file_done != file_size {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} shrunk while reading it", source.display()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes, and compresses if enabled, a single chunk.
+fn hash_chunk(
+    job: Job,
+    hash: HashAlgorithm,
+    compressor: Option<&mut Compressor<'static>>,
+) -> Result<Hashed> {
+    let data = job.data;
+    let zero = data.iter().all(|&x| x == 0);
+    let (chunk_hash, compressed_size) = if zero {
+        (Digest::new(), None)
+    } else {
+        // Only the size is kept, the chunk is compressed again when sent.
+        let compressed_size = match compressor {
+            Some(compressor) => Some(compressor.compress(&data)?.len()).filter(|&x| x < data.len()),
+            None => None,
+        };
+        (hash.hash(&data), compressed_size)
+    };
+    Ok(Hashed {
+        location: job.location,
+        data,
+        zero,
+        hash: chunk_hash,
+        compressed_size,
+    })
+}
+
+/// Hashes chunks until there are no more of them. A failure is sent along
+/// with the results, so that whoever waits for them does not wait forever.
+fn hash_chunks(
+    jobs: Arc<Mutex<Receiver<Job>>>,
+    results: SyncSender<Result<(usize, Hashed)>>,
+    hash: HashAlgorithm,
+    compression_level: Option<i32>,
+) {
+    let mut compressor = match compression_level.map(Compressor::new).transpose() {
+        Ok(compressor) => compressor,
+        Err(e) => {
+            let _ = results.send(Err(e));
+            return;
+        }
+    };
+
+    loop {
+        let Ok(job) = jobs.lock().unwrap().recv() else {
+            return;
+        };
+        let seq = job.seq;
+        let hashed = hash_chunk(job, hash, compressor.as_mut());
+        let failed = hashed.is_err();
+        if results.send(hashed.map(|x| (seq, x))).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Reads the whole image to cut it into chunks and hash them.
+///
+/// Chunks are read ahead by one thread and hashed by several others, then
+/// put back in order to compute the digest of the image and find duplicates.
+pub fn compute_image_metadata(
+    files: Vec<FileMetadata>,
+    sources: &[PathBuf],
+    chunking: Chunking,
+    config: &ServerConfig,
+) -> Result<ImageMetadata> {
+    let image_size: u64 = files
+        .iter()
+        .map(|file| match file.kind {
+            FileKind::Regular { size } => size,
+            _ => 0,
+        })
+        .sum();
+
+    let threads = config
+        .hash_threads
+        .or_else(|| thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(1)
+        .max(1);
+
+    let direct = config.direct_io
+        && match chunking {
+            Chunking::Fixed { size } => size.is_multiple_of(page_size()),
+            Chunking::ContentDefined { .. } => false,
+        };
+    if config.direct_io && !direct {
+        warn!(
+            "Direct I/O requires fixed chunks of a multiple of {} bytes",
+            page_size()
+        );
+    }
+
+    // Enough buffers to keep every thread busy while the next chunks are read.
+    let buffers = threads + 2;
+    let (pool_sender, pool) = mpsc::sync_channel(buffers);
+    for _ in 0..buffers {
+        pool_sender
+            .send(Vec::with_capacity(chunking.max_size()))
+            .unwrap();
+    }
+    let (job_sender, jobs) = mpsc::sync_channel(buffers);
+    let jobs = Arc::new(Mutex::new(jobs));
+    let (result_sender, results) = mpsc::sync_channel(buffers);
+
+    let mut chunk_list: Vec<ChunkMetadata> = Vec::new();
+
+    let mut compressed_total = 0u64;
+    let mut known: HashMap<(Digest, usize), usize> = HashMap::new();
+    let mut total_chunks = 0usize;
+    let mut zero_chunks = 0usize;
+    let mut duplicate_chunks = 0usize;
+
+    let mut digest = config.hash.hasher();
+    let mut done = 0u64;
+
+    let start_time = Instant::now();
+    let mut last_done = done;
+    let mut last_report = start_time;
+
+    info!("Computing image metadata with {} threads", threads);
+
+    thread::scope(|scope| {
+        let reader =
+            scope.spawn(|| read_chunks(&files, sources, chunking, direct, pool, job_sender));
+        let hashers = (0..threads)
+            .map(|_| {
+                let jobs = jobs.clone();
+                let results = result_sender.clone();
+                scope.spawn(move || {
+                    hash_chunks(jobs, results, config.hash, config.compression_level)
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(jobs);
+        drop(result_sender);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let mut failure = None;
+        while let Ok(result) = results.recv() {
+            let (seq, hashed) = match result {
+                Ok(x) => x,
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            };
+            pending.insert(seq, hashed);
+            while let Some(hashed) = pending.remove(&next) {
+                next += 1;
+
+                let now = Instant::now();
+                if now >= last_report + Duration::from_secs(1) {
+                    info!(
+                        "Computing file metadata... {}% ({} MB/s)",
+                        done * 100 / image_size,
+                        (done - last_done) as f32
+                            / (1024f32 * 1024f32)
+                            / (now - last_report).as_secs_f32()
+                    );
+                    last_report = now;
+                    last_done = done;
+                }
+
+                let Hashed {
+                    location,
+                    data,
+                    zero,
+                    hash: chunk_hash,
+                    compressed_size,
+                } = hashed;
+                let size = data.len();
+                digest.update(&data);
+                // The reader may be gone already if hashing failed.
+                let _ = pool_sender.send(data);
+                done += size as u64;
+                total_chunks += 1;
+                zero_chunks += zero as usize;
+
+                // Chunks with the same content are listed, and sent, only once.
+                match known.entry((chunk_hash.clone(), size)) {
+                    Entry::Occupied(x) => {
+                        chunk_list[*x.get()].locations.push(location);
+                        duplicate_chunks += !zero as usize;
+                    }
+                    Entry::Vacant(x) => {
+                        x.insert(chunk_list.len());
+                        if !zero {
+                            compressed_total += compressed_size.unwrap_or(size) as u64;
+                        }
+                        chunk_list.push(ChunkMetadata {
+                            locations: vec![location],
+                            size,
+                            compressed_size,
+                            zero,
+                            hash: chunk_hash,
+                        });
+                    }
+                }
+            }
+        }
+        // Lets the hashers stop if they are still sending results, and the
+        // reader if it is still waiting for a buffer.
+        drop(results);
+        drop(pool_sender);
+
+        for hasher in hashers {
+            hasher.join().unwrap();
+        }
+        let read = reader.join().unwrap();
+        match failure {
+            Some(e) => Err(e),
+            None => read,
+        }
+    })?;
+
+    let end_time = Instant::now();
+
+    info!(
+        "Image metadata generation completed in {} seconds",
+        (end_time - start_time).as_secs_f32()
+    );
+
+    if zero_chunks > 0 {
+        info!(
+            "{} of {} chunks only contain zeros and will not be transmitted",
+            zero_chunks, total_chunks
+        );
+    }
+
+    if duplicate_chunks > 0 {
+        info!(
+            "{} of {} chunks are duplicates and will be transmitted only once",
+            duplicate_chunks, total_chunks
+        );
+    }
+
+    if config.compression_level.is_some() && image_size > 0 {
+        info!(
+            "Chunks compressed to {}% of the image size",
+            compressed_total * 100 / image_size
+        );
+    }
+
+    Ok(ImageMetadata {
+        files: files.into_boxed_slice(),
+        chunks: chunk_list.into_boxed_slice(),
+        fec: None,
+        chunking,
+        hash: config.hash,
+        digest: digest.finalize(),
+    })
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use tokio::time::Instant;
+
+use crate::ClientId;
+
+/// Decides in which order the dispatcher sends the chunks it has queued.
+///
+/// The dispatcher reports every request it queues and asks for the next chunk
+/// whenever it is ready to send one. A chunk it is told about stays pending
+/// until `next` returns it, requests for a pending chunk add to its demand.
+pub trait Scheduler: Send {
+    /// Records a request for `chunk`. `requester` is `None` when the server
+    /// queues the chunk on its own, for every client, such as when streaming
+    /// or in carousel mode.
+    fn request(&mut self, chunk: usize, requester: Option<ClientId>, now: Instant);
+
+    /// Picks the pending chunk to send next, which is no longer pending.
+    fn next(&mut self, now: Instant) -> Option<usize>;
+
+    /// Forgets `client`, which completed the transfer and no longer wants
+    /// the chunks it asked for.
+    fn forget(&mut self, _client: ClientId) {}
+}
+
+/// Built-in scheduling policies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Scheduling {
+    /// Most wanted chunks first, see [`DemandScheduler`].
+    #[default]
+    Demand,
+    /// Pending chunks in turn by index, see [`RoundRobinScheduler`].
+    RoundRobin,
+}
+
+impl Scheduling {
+    pub fn scheduler(self) -> Box<dyn Scheduler> {
+        match self {
+            Scheduling::Demand => Box::new(DemandScheduler::default()),
+            Scheduling::RoundRobin => Box::new(RoundRobinScheduler::default()),
+        }
+    }
+}
+
+/// Sends pending chunks in turn by index, regardless of how many clients
+/// want them.
+#[derive(Default)]
+pub struct RoundRobinScheduler {
+    pending: BTreeSet<usize>,
+    last: usize,
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn request(&mut self, chunk: usize, _requester: Option<ClientId>, _now: Instant) {
+        self.pending.insert(chunk);
+    }
+
+    fn next(&mut self, _now: Instant) -> Option<usize> {
+        let next = self
+            .pending
+            .range((Bound::Excluded(self.last), Bound::Unbounded))
+            .next()
+            .or_else(|| self.pending.first())
+            .copied()?;
+        self.pending.remove(&next);
+        self.last = next;
+        Some(next)
+    }
+}
+
+/// Clients that sent no request for this long no longer count towards the
+/// demand for chunks queued by the server.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Demand {
+    requesters: BTreeSet<ClientId>,
+    /// The server queued the chunk for every client.
+    pushed: bool,
+    first_request: Instant,
+}
+
+/// Sends the chunks wanted by the most clients first, so that a few clients
+/// missing a lot do not hold up the others.
+///
+/// Each pending chunk scores the number of distinct clients that asked for it
+/// times one plus the seconds since the oldest of these requests, so that
+/// chunks only a few clients want are still sent eventually. Chunks queued by
+/// the server count as wanted by every client heard from lately. Ties go to
+/// the oldest request, then to the lowest index.
+///
+/// Among chunks wanted by as many clients, the oldest request always scores
+/// highest. Pending chunks are therefore grouped by their number of
+/// requesters and ordered by age, only the first of each group competes.
+#[derive(Default)]
+pub struct DemandScheduler {
+    pending: BTreeMap<usize, Demand>,
+    /// Chunks clients asked for, by number of requesters, oldest first.
+    requested: BTreeMap<usize, BTreeSet<(Instant, usize)>>,
+    /// Chunks the server queued, oldest first.
+    pushed: BTreeSet<(Instant, usize)>,
+    /// Clients heard from lately, with the time of their last request.
+    clients: BTreeMap<ClientId, Instant>,
+    /// The same clients, least recently heard from first.
+    last_requests: BTreeSet<(Instant, ClientId)>,
+}
+
+impl DemandScheduler {
+    fn link(&mut self, chunk: usize, demand: &Demand) {
+        let key = (demand.first_request, chunk);
+        if demand.pushed {
+            self.pushed.insert(key);
+        } else {
+            self.requested
+                .entry(demand.requesters.len())
+                .or_default()
+                .insert(key);
+        }
+    }
+
+    fn unlink(&mut self, chunk: usize, demand: &Demand) {
+        let key = (demand.first_request, chunk);
+        if demand.pushed {
+            self.pushed.remove(&key);
+        } else if let Some(group) = self.requested.get_mut(&demand.requesters.len()) {
+            group.remove(&key);
+            if group.is_empty() {
+                self.requested.remove(&demand.requesters.len());
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(last, client)) = self.last_requests.first()
+            && now.saturating_duration_since(last) >= CLIENT_TIMEOUT
+        {
+            self.forget(client);
+        }
+    }
+}
+
+impl Scheduler for DemandScheduler {
+    fn request(&mut self, chunk: usize, requester: Option<ClientId>, now: Instant) {
+        let mut demand = match self.pending.remove(&chunk) {
+            Some(demand) => {
+                self.unlink(chunk, &demand);
+                demand
+            }
+            None => Demand {
+                requesters: BTreeSet::new(),
+                pushed: false,
+                first_request: now,
+            },
+        };
+        match requester {
+            Some(requester) => {
+                demand.requesters.insert(requester);
+                if let Some(last) = self.clients.insert(requester, now) {
+                    self.last_requests.remove(&(last, requester));
+                }
+                self.last_requests.insert((now, requester));
+            }
+            None => demand.pushed = true,
+        }
+        self.link(chunk, &demand);
+        self.pending.insert(chunk, demand);
+    }
+
+    fn next(&mut self, now: Instant) -> Option<usize> {
+        self.expire(now);
+
+        let score = |requesters: usize, first_request: Instant| {
+            let age = now.saturating_duration_since(first_request);
+            requesters as f64 * (1.0 + age.as_secs_f64())
+        };
+        let candidates = self
+            .requested
+            .iter()
+            .filter_map(|(&requesters, group)| group.first().map(|&x| (requesters, x)))
+            .chain(self.pushed.first().map(|&x| (self.clients.len().max(1), x)));
+
+        let mut best: Option<(f64, (Instant, usize))> = None;
+        for (requesters, key) in candidates {
+            let score = score(requesters, key.0);
+            if best.is_none_or(|(best_score, best_key)| {
+                score > best_score || (score == best_score && key < best_key)
+            }) {
+                best = Some((score, key));
+            }
+        }
+
+        let (_, (_, chunk)) = best?;
+        let demand = self.pending.remove(&chunk).unwrap();
+        self.unlink(chunk, &demand);
+        Some(chunk)
+    }
+
+    fn forget(&mut self, client: ClientId) {
+        if let Some(last) = self.clients.remove(&client) {
+            self.last_requests.remove(&(last, client));
+        }
+        let chunks: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, demand)| demand.requesters.contains(&client))
+            .map(|(&chunk, _)| chunk)
+            .collect();
+        for chunk in chunks {
+            let mut demand = self.pending.remove(&chunk).unwrap();
+            self.unlink(chunk, &demand);
+            demand.requesters.remove(&client);
+            self.link(chunk, &demand);
+            self.pending.insert(chunk, demand);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(scheduler: &mut dyn Scheduler, now: Instant) -> Vec<usize> {
+        std::iter::from_fn(|| scheduler.next(now)).collect()
+    }
+
+    #[test]
+    fn round_robin_wraps_around() {
+        let now = Instant::now();
+        let mut scheduler = RoundRobinScheduler::default();
+        for chunk in [5, 1, 3] {
+            scheduler.request(chunk, Some(1), now);
+        }
+        assert_eq!(scheduler.next(now), Some(1));
+        scheduler.request(0, None, now);
+        scheduler.request(2, Some(2), now);
+        assert_eq!(drain(&mut scheduler, now), vec![2, 3, 5, 0]);
+    }
+
+    #[test]
+    fn demand_prefers_most_requested() {
+        let now = Instant::now();
+        let mut scheduler = DemandScheduler::default();
+        assert_eq!(scheduler.next(now), None);
+        // Asking again does not make a client count twice.
+        for _ in 0..3 {
+            scheduler.request(1, Some(1), now);
+        }
+        scheduler.request(2, Some(1), now);
+        scheduler.request(2, Some(2), now);
+        scheduler.request(3, Some(1), now);
+        scheduler.request(3, Some(2), now);
+        scheduler.request(3, Some(3), now);
+        assert_eq!(drain(&mut scheduler, now), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn demand_breaks_ties() {
+        let start = Instant::now();
+        let mut scheduler = DemandScheduler::default();
+        scheduler.request(5, Some(1), start);
+        scheduler.request(3, Some(1), start);
+        assert_eq!(drain(&mut scheduler, start), vec![3, 5]);
+
+        // Both score 4 after 3 seconds, the oldest request goes first.
+        scheduler.request(7, Some(1), start);
+        let later = start + Duration::from_secs(2);
+        scheduler.request(2, Some(1), later);
+        scheduler.request(2, Some(2), later);
+        assert_eq!(
+            drain(&mut scheduler, start + Duration::from_secs(3)),
+            vec![7, 2]
+        );
+    }
+
+    #[test]
+    fn demand_does_not_starve_chunks() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(10);
+        let mut scheduler = DemandScheduler::default();
+        scheduler.request(1, Some(1), start);
+        for client in 1..=3 {
+            scheduler.request(2, Some(client), now);
+        }
+        assert_eq!(drain(&mut scheduler, now), vec![1, 2]);
+    }
+
+    #[test]
+    fn demand_counts_pushed_chunks_for_everyone() {
+        let now = Instant::now();
+        let mut scheduler = DemandScheduler::default();
+        scheduler.request(4, None, now);
+        assert_eq!(scheduler.next(now), Some(4));
+
+        scheduler.request(1, Some(1), now);
+        scheduler.request(1, Some(2), now);
+        scheduler.request(2, Some(3), now);
+        scheduler.request(4, None, now);
+        assert_eq!(drain(&mut scheduler, now), vec![4, 1, 2]);
+    }
+
+    #[test]
+    fn demand_forgets_completed_clients() {
+        let now = Instant::now();
+        let mut scheduler = DemandScheduler::default();
+        for client in [4, 5] {
+            scheduler.request(2, Some(client), now);
+        }
+        for client in [1, 2, 3] {
+            scheduler.request(1, Some(client), now);
+        }
+        scheduler.forget(1);
+        scheduler.forget(2);
+        assert_eq!(drain(&mut scheduler, now), vec![2, 1]);
+    }
+
+    #[test]
+    fn demand_forgets_silent_clients() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(4);
+        let mut scheduler = DemandScheduler::default();
+        scheduler.request(1, Some(1), start);
+        scheduler.request(2, Some(2), later);
+        scheduler.request(2, Some(3), later);
+        scheduler.request(3, None, later);
+        // Client 1 is gone, the pushed chunk only counts the other two.
+        assert_eq!(
+            drain(&mut scheduler, start + Duration::from_secs(6)),
+            vec![2, 3, 1]
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::VecDeque,
+    io::Read,
+    ops::ControlFlow,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::info;
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::{oneshot, watch},
+    time::{Instant, sleep, sleep_until},
+};
+use zstd::bulk::Compressor;
+
+use super::ServerState;
+use crate::{
+    ChunkLocation, ChunkMetadata, STREAM_SIGNATURE_CONTEXT, Signed, StreamAnnouncement, StreamEnd,
+    hash::Digest,
+};
+
+/// Time between announcements of the window while no chunks are added.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time a chunk stays in the window after it was last sent or requested, for
+/// clients that missed some of it to ask for it again.
+const RETRANSMIT_GRACE: Duration = Duration::from_millis(500);
+
+struct WindowChunk {
+    metadata: ChunkMetadata,
+    /// The chunk as sent on the wire, compressed if that makes it smaller.
+    payload: Arc<[u8]>,
+    /// Last time the chunk was sent, or announced for chunks of zeros, or
+    /// requested again since.
+    sent: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Window {
+    /// Index of the first chunk of `chunks`.
+    first: usize,
+    chunks: VecDeque<WindowChunk>,
+    end: Option<StreamEnd>,
+}
+
+/// The chunks of the stream that were read and are still available.
+pub struct StreamState {
+    window: Mutex<Window>,
+    /// Signalled whenever a chunk has been sent.
+    sent: Condvar,
+    /// Amount of chunks read so far, also bumped when the end is reached.
+    produced: watch::Sender<usize>,
+    /// Amount of distinct clients heard from so far.
+    clients: watch::Sender<usize>,
+    /// Clients to wait for before starting the stream.
+    expected_clients: usize,
+}
+
+impl StreamState {
+    pub fn new(expected_clients: Option<usize>) -> StreamState {
+        StreamState {
+            window: Mutex::default(),
+            sent: Condvar::new(),
+            produced: watch::Sender::default(),
+            clients: watch::Sender::default(),
+            expected_clients: expected_clients.unwrap_or(1),
+        }
+    }
+
+    pub fn produced(&self) -> watch::Receiver<usize> {
+        self.produced.subscribe()
+    }
+
+    /// Records the amount of distinct clients that sent a packet so far.
+    pub fn clients_joined(&self, count: usize) {
+        self.clients.send_if_modified(|clients| {
+            let modified = *clients != count;
+            *clients = count;
+            modified
+        });
+    }
+
+    /// Whether as many clients as expected, or a single one, joined the
+    /// stream. Chunks sent before then could leave the window before the
+    /// clients have a chance to get them.
+    pub fn started(&self) -> bool {
+        *self.clients.borrow() >= self.expected_clients
+    }
+
+    pub async fn wait_for_clients(&self) {
+        let _ = self
+            .clients
+            .subscribe()
+            .wait_for(|&clients| clients >= self.expected_clients)
+            .await;
+    }
+
+    fn with_chunk<T>(&self, index: usize, f: impl FnOnce(&mut WindowChunk) -> T) -> Option<T> {
+        let mut window = self.window.lock().unwrap();
+        let position = index.checked_sub(window.first)?;
+        window.chunks.get_mut(position).map(f)
+    }
+
+    /// Size on the wire of a chunk of the window that is not all zeros.
+    pub fn wire_size(&self, index: usize) -> Option<usize> {
+        self.with_chunk(index, |chunk| {
+            (!chunk.metadata.zero).then(|| chunk.metadata.wire_size())
+        })
+        .flatten()
+    }
+
+    pub fn payload(&self, index: usize) -> Option<Arc<[u8]>> {
+        self.with_chunk(index, |chunk| chunk.payload.clone())
+    }
+
+    /// Keeps a chunk that was already sent in the window for a while longer,
+    /// as some clients still miss it.
+    pub fn mark_requested(&self, index: usize) {
+        self.with_chunk(index, |chunk| {
+            if chunk.sent.is_some() {
+                chunk.sent = Some(Instant::now());
+            }
+        });
+    }
+
+    pub fn mark_sent(&self, index: usize) {
+        if self
+            .with_chunk(index, |chunk| chunk.sent = Some(Instant::now()))
+            .is_some()
+        {
+            self.sent.notify_all();
+        }
+    }
+}
+
+/// Cuts the input into chunks and adds them to the window until its end.
+fn fill_window(
+    state: &ServerState,
+    stream: &StreamState,
+    input: Box<dyn Read + Send>,
+) -> Result<()> {
+    let hash = state.image.hash;
+    let window_size = state.config.stream_window;
+    let mut compressor = state
+        .config
+        .compression_level
+        .map(Compressor::new)
+        .transpose()?;
+    let mut digest = hash.hasher();
+    let mut count = 0;
+    let mut size = 0u64;
+    let mut last_report = Instant::now();
+
+    info!("Streaming input with a window of {} chunks", window_size);
+
+    state.image.chunking.split(input, |offset, data| {
+        digest.update(data);
+
+        let zero = data.iter().all(|&x| x == 0);
+        let (chunk_hash, compressed) = if zero {
+            (Digest::new(), None)
+        } else {
+            let compressed = match &mut compressor {
+                Some(compressor) => {
+                    Some(compressor.compress(data)?).filter(|x| x.len() < data.len())
+                }
+                None => None,
+            };
+            (hash.hash(data), compressed)
+        };
+        let chunk = WindowChunk {
+            metadata: ChunkMetadata {
+                locations: vec![ChunkLocation { file: 0, offset }],
+                size: data.len(),
+                compressed_size: compressed.as_ref().map(Vec::len),
+                zero,
+                hash: chunk_hash,
+            },
+            payload: match compressed {
+                Some(compressed) => compressed.into(),
+                None if zero => Arc::new([]),
+                None => data.into(),
+            },
+            sent: None,
+        };
+
+        let mut window = stream.window.lock().unwrap();
+        // The oldest chunk only leaves once every client had a chance to get it.
+        while window.chunks.len() >= window_size {
+            if state.token.is_cancelled() {
+                return Ok(ControlFlow::Break(()));
+            }
+            let wait = match window.chunks[0].sent {
+                Some(sent) => (sent + RETRANSMIT_GRACE).saturating_duration_since(Instant::now()),
+                None => ANNOUNCE_INTERVAL,
+            };
+            if wait.is_zero() {
+                break;
+            }
+            window = stream
+                .sent
+                .wait_timeout(window, wait.min(ANNOUNCE_INTERVAL))
+                .unwrap()
+                .0;
+        }
+        if window.chunks.len() >= window_size {
+            window.chunks.pop_front();
+            window.first += 1;
+        }
+        window.chunks.push_back(chunk);
+        drop(window);
+
+        count += 1;
+        size += data.len() as u64;
+        stream.produced.send_replace(count);
+
+        let now = Instant::now();
+        if now >= last_report + Duration::from_secs(1) {
+            info!("Streaming input... {} bytes read", size);
+            last_report = now;
+        }
+
+        Ok(ControlFlow::Continue(()))
+    })?;
+
+    if state.token.is_cancelled() {
+        return Ok(());
+    }
+
+    stream.window.lock().unwrap().end = Some(StreamEnd {
+        chunks: count,
+        size,
+        digest: digest.finalize(),
+    });
+    stream.produced.send_modify(|_| {});
+
+    info!("End of stream after {} bytes in {} chunks", size, count);
+
+    Ok(())
+}
+
+/// Reads the input to stream, if any, until its end.
+pub async fn read_stream(
+    state: Arc<ServerState>,
+    input: Option<Box<dyn Read + Send>>,
+) -> Result<()> {
+    let Some(input) = input else {
+        return Ok(());
+    };
+
+    let token = state.token.clone();
+    let (sender, receiver) = oneshot::channel();
+    // Not a blocking task, which would hold up the runtime when shutting down
+    // while the input has nothing to read.
+    thread::spawn(move || {
+        let stream = state
+            .stream
+            .as_ref()
+            .expect("Invalid global state (streaming without a window)");
+        let _ = sender.send(fill_window(&state, stream, input));
+    });
+
+    select! {
+        biased;
+        _ = token.cancelled() => Ok(()),
+        x = receiver => x?,
+    }
+}
+
+/// Announces the chunks of the window on the metadata group, again whenever
+/// chunks are added and every `ANNOUNCE_INTERVAL` otherwise.
+pub async fn announce(state: &ServerState, stream: &StreamState, socket: &UdpSocket) -> Result<()> {
+    let mut produced = stream.produced();
+    let max_size = state.config.max_udp_payload_size as usize;
+    // Room taken by the signature and the length of the payload.
+    let overhead = postcard::to_allocvec(&Signed::new(
+        STREAM_SIGNATURE_CONTEXT,
+        &[],
+        state.config.signing_key.as_ref(),
+    ))?
+    .len()
+        + 2;
+    let mut next = Instant::now();
+
+    info!("Announcing the stream on group {}", socket.peer_addr()?);
+
+    loop {
+        produced.borrow_and_update();
+        let (window_start, chunks, end) = {
+            let mut window = stream.window.lock().unwrap();
+            // Chunks of zeros are never sent, clients fill them in as soon as
+            // they are announced.
+            if stream.started() {
+                let now = Instant::now();
+                for chunk in window.chunks.iter_mut().filter(|x| x.metadata.zero) {
+                    chunk.sent.get_or_insert(now);
+                }
+            }
+            (
+                window.first,
+                window
+                    .chunks
+                    .iter()
+                    .map(|chunk| chunk.metadata.clone())
+                    .collect::<Vec<_>>(),
+                window.end.clone(),
+            )
+        };
+
+        next = next.max(Instant::now());
+        let mut start = 0;
+        // At least one announcement is sent, even for an empty window.
+        loop {
+            let mut announcement = StreamAnnouncement {
+                session: state.session,
+                chunking: state.image.chunking,
+                hash: state.image.hash,
+                fec: state.image.fec,
+                window_start,
+                first: window_start + start,
+                chunks: Vec::new(),
+                end: end.clone(),
+            };
+            let mut payload = postcard::to_allocvec(&announcement)?;
+            while start + announcement.chunks.len() < chunks.len() {
+                announcement
+                    .chunks
+                    .push(chunks[start + announcement.chunks.len()].clone());
+                let larger = postcard::to_allocvec(&announcement)?;
+                if larger.len() + overhead > max_size && announcement.chunks.len() > 1 {
+                    announcement.chunks.pop();
+                    break;
+                }
+                payload = larger;
+            }
+            start += announcement.chunks.len();
+
+            let packet = postcard::to_allocvec(&Signed::new(
+                STREAM_SIGNATURE_CONTEXT,
+                &payload,
+                state.config.signing_key.as_ref(),
+            ))?;
+
+            select! {
+                biased;
+                _ = state.token.cancelled() => return Ok(()),
+                _ = sleep_until(next) => {},
+            }
+            let sent = socket.send(&packet).await?;
+            next += 8 * sent as u32 * Duration::from_secs(1) / state.config.metadata_rate;
+
+            if start >= chunks.len() {
+                break;
+            }
+        }
+
+        select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(()),
+            _ = produced.changed() => {},
+            _ = sleep(ANNOUNCE_INTERVAL) => {},
+        }
+    }
+}
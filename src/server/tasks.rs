@@ -0,0 +1,223 @@
+mod chunk;
+mod lru;
+mod rate;
+
+use std::{
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{info, trace};
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpListener,
+    select,
+    task::JoinSet,
+    time::{Instant, sleep, sleep_until},
+};
+
+use crate::{
+    DISCOVERY_SIGNATURE_CONTEXT, MetadataBlock, ServerDiscovery, Signed,
+    net::new_sender_multicast_socket,
+    server::{ServerState, stream::announce},
+};
+
+pub use chunk::{chunk_request_server, max_fragment_size};
+
+pub async fn spawn(handle: impl Future<Output = Result<()>> + Send + 'static) -> Result<()> {
+    tokio::spawn(handle).await?
+}
+
+pub async fn server_discovery(state: Arc<ServerState>) -> Result<()> {
+    let bind_address = match state.unicast {
+        IpAddr::V4(x) => SocketAddr::V4(SocketAddrV4::new(x, 0)),
+        IpAddr::V6(x) => SocketAddr::V6(SocketAddrV6::new(
+            x,
+            0,
+            0,
+            if x.is_unicast_link_local() {
+                state.interface.index
+            } else {
+                0
+            },
+        )),
+    };
+    let socket = new_sender_multicast_socket(
+        state.config.discovery_socket,
+        bind_address,
+        state.interface_id,
+        state.config.max_hops,
+    )
+    .await?;
+
+    let token = state.token.clone();
+
+    let discovery = postcard::to_allocvec(&select! {
+        biased;
+        _ = token.cancelled() => { return Ok(()); },
+        x = async {
+            ServerDiscovery {
+                session: state.session,
+                metadata_socket: if state.config.tcp_metadata && state.stream.is_none() {
+                    Some(*state.metadata_socket.wait().await)
+                } else {
+                    None
+                },
+                metadata_group: state.config.metadata_group,
+                request_group: state.config.request_group,
+                metadata_digest: *blake3::hash(&state.signed_metadata).as_bytes(),
+                request_socket: *state.request_socket.wait().await,
+                transfer_socket: state.config.transfer_socket,
+                stream: state.stream.is_some(),
+            }
+        } => x,
+    })?;
+    let data = postcard::to_allocvec(&Signed::new(
+        DISCOVERY_SIGNATURE_CONTEXT,
+        &discovery,
+        state.config.signing_key.as_ref(),
+    ))?;
+
+    info!(
+        "Start sending discovery packets every {} milliseconds on interface {} from address {}",
+        state.config.discovery_interval.as_millis(),
+        state.interface.name,
+        state.unicast
+    );
+
+    loop {
+        let _ = socket.send(data.as_slice()).await?;
+
+        select! {
+            biased;
+            _ = token.cancelled() => return Ok(()),
+            _ = sleep(state.config.discovery_interval) => {},
+        }
+    }
+}
+
+pub async fn metadata_server(state: Arc<ServerState>) -> Result<()> {
+    if !state.config.tcp_metadata || state.stream.is_some() {
+        return Ok(());
+    }
+
+    let bind_address = match state.unicast {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, 0)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            0,
+            0,
+            if ip.is_unicast_link_local() {
+                state.interface.index
+            } else {
+                0
+            },
+        )),
+    };
+    let socket = TcpListener::bind(bind_address).await?;
+    state
+        .metadata_socket
+        .set(socket.local_addr()?)
+        .expect("Invalid global server state (metadata socket address was already set)");
+
+    let token = state.token.clone();
+
+    let mut clients = JoinSet::<()>::new();
+
+    info!(
+        "Listening for metadata transfers on {}",
+        state.metadata_socket.get().unwrap()
+    );
+
+    loop {
+        select! {
+            biased;
+            _ = token.cancelled() => break,
+            _ = clients.join_next(), if !clients.is_empty() => {},
+            conn = socket.accept() => if let Ok((mut stream, addr)) = conn {
+                trace!("New metadata transfer to {}", addr);
+                let state = state.clone();
+                clients.spawn(async move {
+                    let buf = &state.signed_metadata;
+                    let mut pos: usize = 0;
+                    while pos < buf.len() {
+                        let Ok(written) = stream.write(&buf[pos..]).await else { break; };
+                        if written == 0 { break; }
+                        pos += written;
+                    }
+                });
+            }
+        }
+    }
+
+    clients.shutdown().await;
+
+    Ok(())
+}
+
+/// Sends the signed image metadata on the metadata group over and over, for
+/// clients to pick up without connecting to the server.
+pub async fn metadata_carousel(state: Arc<ServerState>) -> Result<()> {
+    let Some(group) = state.config.metadata_group else {
+        return Ok(());
+    };
+
+    let bind_address = match state.unicast {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, 0)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            0,
+            0,
+            if ip.is_unicast_link_local() {
+                state.interface.index
+            } else {
+                0
+            },
+        )),
+    };
+    let socket = new_sender_multicast_socket(
+        group,
+        bind_address,
+        state.interface_id,
+        state.config.max_hops,
+    )
+    .await?;
+
+    if let Some(stream) = &state.stream {
+        return announce(&state, stream, &socket).await;
+    }
+
+    // The header of a block is smaller than the one of a chunk fragment.
+    let block_size = max_fragment_size(state.config.max_udp_payload_size);
+    let metadata = &state.signed_metadata;
+    let mut send_buf = vec![0u8; state.config.max_udp_payload_size as usize];
+    let mut next = Instant::now();
+
+    info!(
+        "Carouselling {} bytes of image metadata on group {}",
+        metadata.len(),
+        group
+    );
+
+    loop {
+        for (i, data) in metadata.chunks(block_size).enumerate() {
+            let block = MetadataBlock {
+                session: state.session,
+                size: metadata.len(),
+                offset: i * block_size,
+                data,
+            };
+            let packet = postcard::to_slice(&block, &mut send_buf)?;
+
+            select! {
+                biased;
+                _ = state.token.cancelled() => return Ok(()),
+                _ = sleep_until(next) => {},
+            }
+            let sent = socket.send(packet).await?;
+            next += 8 * sent as u32 * Duration::from_secs(1) / state.config.metadata_rate;
+        }
+    }
+}
@@ -0,0 +1,655 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::{self, ErrorKind, SeekFrom},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, atomic::Ordering},
+    time::Duration,
+};
+
+use anyhow::{Result, bail, ensure};
+use log::{debug, info, warn};
+use raptorq::Encoder;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::UdpSocket,
+    select,
+    sync::mpsc::{Receiver, Sender, channel},
+    time::{Instant, sleep_until},
+    try_join,
+};
+use zstd::bulk::Compressor;
+
+use super::{lru::ChunkCache, rate::RateController};
+use crate::{
+    ChunkData, ClientId, ClientMessage, ClientPacket, FragmentRange, SessionId,
+    net::{new_receiver_multicast_socket, new_sender_multicast_socket},
+    server::{Scheduler, ServerState},
+};
+
+const RATE_ADJUST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Memory taken at most by the FEC encoders kept for the chunks sent lately.
+const ENCODER_CACHE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Memory taken at most by the compressed chunks kept for retransmissions.
+const COMPRESSED_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Largest fragment of chunk data whose encoded `ChunkData` fits in a datagram
+/// of `max_udp_payload_size` bytes.
+pub fn max_fragment_size(max_udp_payload_size: u16) -> usize {
+    let mut l = u16::MIN;
+    let mut r = u16::MAX;
+
+    let test_buf = [0u8; u16::MAX as usize];
+    let mut test_buf2 = [0u8; u16::MAX as usize];
+
+    while r - l > 1 {
+        let m = (r - l) / 2 + l;
+
+        if let Ok(x) = postcard::to_slice(
+            &ChunkData {
+                session: SessionId {
+                    session: u64::MAX,
+                    image: u64::MAX,
+                },
+                seq: u64::MAX,
+                chunk: usize::MAX,
+                offset: usize::MAX,
+                data: &test_buf[0..m as usize],
+            },
+            &mut test_buf2,
+        ) && x.len() <= max_udp_payload_size as usize
+        {
+            l = m;
+        } else {
+            r = m;
+        }
+    }
+
+    l as usize
+}
+
+/// A range of a chunk to send, with the client that asked for it if any.
+struct ChunkRequest {
+    range: FragmentRange,
+    requester: Option<ClientId>,
+}
+
+/// What the dispatcher is told about.
+enum Dispatch {
+    Request(ChunkRequest),
+    /// The client completed the transfer, it no longer wants any chunk.
+    Completed(ClientId),
+}
+
+/// Merges the half-open range `[start, end)` into a sorted list of disjoint ranges.
+fn insert_range(ranges: &mut Vec<(usize, usize)>, mut start: usize, mut end: usize) {
+    ranges.retain(|&(s, e)| {
+        if s <= end && start <= e {
+            start = start.min(s);
+            end = end.max(e);
+            false
+        } else {
+            true
+        }
+    });
+    let pos = ranges.partition_point(|&(s, _)| s < start);
+    ranges.insert(pos, (start, end));
+}
+
+/// Receives a packet from whichever of the request socket and the request
+/// group has one first.
+async fn recv_request(
+    socket: &UdpSocket,
+    group: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    let Some(group) = group else {
+        return socket.recv_from(buf).await;
+    };
+    loop {
+        let ready = select! {
+            x = socket.readable() => x.map(|_| socket),
+            x = group.readable() => x.map(|_| group),
+        }?;
+        match ready.try_recv_from(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            x => return x,
+        }
+    }
+}
+
+async fn request_listener(
+    state: &Arc<ServerState>,
+    bind: SocketAddr,
+    sender: Sender<Dispatch>,
+) -> Result<()> {
+    let socket = UdpSocket::bind(bind).await?;
+    state
+        .request_socket
+        .set(socket.local_addr()?)
+        .expect("Invalid global state (request socket was already set)");
+
+    let group = match state.config.request_group {
+        Some(group) => Some(new_receiver_multicast_socket(group, state.interface_id).await?),
+        None => None,
+    };
+
+    let mut buf = vec![0u8; 128];
+
+    let mut rate = state.config.min_flood_speed.map(|floor| {
+        RateController::new(
+            floor,
+            state.config.flood_speed,
+            state.config.target_loss,
+            state.config.drop_slow_after,
+        )
+    });
+    let mut next_adjust = Instant::now();
+
+    let mut completed: BTreeSet<ClientId> = BTreeSet::new();
+    let mut joined: BTreeSet<ClientId> = BTreeSet::new();
+    let idle_timeout = state.config.idle_timeout;
+    let mut last_request = Instant::now();
+
+    info!("Listening for chunk requests on {}", socket.local_addr()?);
+    if let Some(group) = state.config.request_group {
+        info!("Listening for chunk requests on group {}", group);
+    }
+
+    loop {
+        select! {
+            biased;
+            _ = state.token.cancelled() => break,
+            _ = sleep_until(last_request + idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                info!(
+                    "No requests received for {} seconds, shutting down ({} clients completed the transfer)",
+                    idle_timeout.unwrap().as_secs(),
+                    completed.len()
+                );
+                state.token.cancel();
+                break;
+            },
+            _ = sleep_until(next_adjust), if rate.is_some() => {
+                let rate = rate.as_mut().unwrap().adjust(next_adjust);
+                state.rate.store(rate, Ordering::Relaxed);
+                next_adjust += RATE_ADJUST_INTERVAL;
+            },
+            x = recv_request(&socket, group.as_ref(), &mut buf) => {
+                let Ok((sz, addr)) = x else { continue };
+                let packet: ClientPacket = match postcard::from_bytes(&buf[0..sz]) {
+                    Ok(x) => x,
+                    Err(postcard::Error::DeserializeUnexpectedEnd) => {
+                        buf.resize(2 * buf.len(), 0);
+                        continue;
+                    },
+                    _ => continue,
+                };
+                if packet.session != state.session {
+                    continue;
+                }
+                if let Some(stream) = &state.stream {
+                    joined.insert(packet.client);
+                    stream.clients_joined(joined.len());
+                    // Requests are ignored until the stream starts, clients send them again.
+                    if !stream.started() {
+                        continue;
+                    }
+                }
+                match packet.message {
+                    // Every chunk is sent anyway in carousel mode.
+                    ClientMessage::Request(_) if state.config.carousel => {},
+                    ClientMessage::Request(ranges) => {
+                        last_request = Instant::now();
+                        for range in ranges {
+                            if state.stream.is_none() && range.chunk >= state.image.chunks.len() {
+                                warn!("Received request for chunk id {} which is invalid", range.chunk);
+                                continue;
+                            }
+                            if state.wire_size(range.chunk).is_none() {
+                                continue;
+                            }
+                            if let Some(stream) = &state.stream {
+                                stream.mark_requested(range.chunk);
+                            }
+                            let request = ChunkRequest { range, requester: Some(packet.client) };
+                            if sender.send(Dispatch::Request(request)).await.is_err() { break }
+                        }
+                    },
+                    ClientMessage::Report(report) => {
+                        if let Some(rate) = &mut rate {
+                            rate.report(packet.client, addr, report, Instant::now());
+                        }
+                    },
+                    ClientMessage::Completed => {
+                        if !completed.insert(packet.client) {
+                            continue;
+                        }
+                        info!("Client {} completed the transfer ({} so far)", addr.ip(), completed.len());
+                        state.progress.send_modify(|progress| progress.completed_clients = completed.len());
+                        if sender.send(Dispatch::Completed(packet.client)).await.is_err() {
+                            break;
+                        }
+                        if let Some(expected) = state.config.expect_clients
+                            && completed.len() >= expected
+                        {
+                            info!("All {} expected clients completed the transfer, shutting down", expected);
+                            state.token.cancel();
+                            break;
+                        }
+                    },
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a chunk of the image and compresses it if needed, reusing the file
+/// of the previous chunk when possible. Compressed chunks are kept in
+/// `compressed` for the next time they are sent.
+///
+/// Fails when the chunk does not compress to the size recorded in the
+/// metadata, clients could not decode it and would wait for it forever.
+async fn read_chunk<'a>(
+    state: &ServerState,
+    file: &mut Option<(usize, File)>,
+    chunk_buf: &'a mut [u8],
+    compressor: &mut Option<Compressor<'_>>,
+    compressed: &'a mut ChunkCache<Vec<u8>>,
+    index: usize,
+) -> Result<Cow<'a, [u8]>> {
+    let chunk = &state.image.chunks[index];
+    if compressed.get(index).is_some() {
+        return Ok(Cow::Borrowed(&compressed.get(index).unwrap()[..]));
+    }
+
+    let location = chunk.locations[0];
+    let file = match file {
+        Some((file_index, file)) if *file_index == location.file => file,
+        _ => {
+            &mut file
+                .insert((
+                    location.file,
+                    File::open(&state.sources[location.file]).await?,
+                ))
+                .1
+        }
+    };
+    file.seek(SeekFrom::Start(location.offset)).await?;
+
+    let mut count: usize = 0;
+
+    while count < chunk.size {
+        let bytes_read = file.read(&mut chunk_buf[count..chunk.size]).await?;
+        ensure!(
+            bytes_read != 0,
+            "Invalid global state (chunk extends beyond file boundaries)"
+        );
+        count += bytes_read;
+    }
+
+    // Compression is deterministic, this yields the same bytes whose size
+    // was recorded in the metadata, unless another version of zstd measured it.
+    let Some(compressed_size) = chunk.compressed_size else {
+        return Ok(Cow::Borrowed(&chunk_buf[0..chunk.size]));
+    };
+    let Some(compressor) = compressor else {
+        bail!(
+            "Chunk {} is compressed in the image metadata, but compression is disabled",
+            index
+        );
+    };
+    let data = compressor.compress(&chunk_buf[0..chunk.size])?;
+    ensure!(
+        data.len() == compressed_size,
+        "Chunk {} compresses to {} bytes instead of the {} recorded in the image metadata, which was likely computed with another version of zstd",
+        index,
+        data.len(),
+        compressed_size
+    );
+    let size = data.len();
+    Ok(Cow::Borrowed(&compressed.insert(index, data, size)[..]))
+}
+
+/// Queues the requested range and records the demand for its chunk, or has
+/// the scheduler forget a client that completed.
+fn handle_message(
+    state: &ServerState,
+    queue: &mut BTreeMap<usize, Vec<(usize, usize)>>,
+    scheduler: &mut dyn Scheduler,
+    message: Dispatch,
+) {
+    let request = match message {
+        Dispatch::Request(request) => request,
+        Dispatch::Completed(client) => return scheduler.forget(client),
+    };
+    let range = request.range;
+    let Some(size) = state.wire_size(range.chunk) else {
+        return;
+    };
+    let end = range.offset.saturating_add(range.len).min(size);
+    if range.offset < end {
+        insert_range(queue.entry(range.chunk).or_default(), range.offset, end);
+        scheduler.request(range.chunk, request.requester, Instant::now());
+    }
+}
+
+async fn chunk_dispatcher(
+    state: &Arc<ServerState>,
+    bind: SocketAddr,
+    mut receiver: Receiver<Dispatch>,
+    mut scheduler: Box<dyn Scheduler>,
+) -> Result<()> {
+    let max_fragment_size = max_fragment_size(state.config.max_udp_payload_size);
+
+    let mut file: Option<(usize, File)> = None;
+
+    let socket = new_sender_multicast_socket(
+        state.config.transfer_socket,
+        bind,
+        state.interface_id,
+        state.config.max_hops,
+    )
+    .await?;
+
+    let mut queue: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+    let mut next_repair: BTreeMap<usize, u32> = BTreeMap::new();
+    let mut rounds: u64 = 0;
+    let mut seq: u64 = 0;
+    let mut sleep = Instant::now();
+
+    let mut send_buf: Box<[u8]> =
+        vec![0u8; state.config.max_udp_payload_size as usize].into_boxed_slice();
+    let mut chunk_buf: Box<[u8]> = vec![0u8; state.image.chunking.max_size()].into_boxed_slice();
+    let mut compressor = state
+        .config
+        .compression_level
+        .map(Compressor::new)
+        .transpose()?;
+    let mut encoders = ChunkCache::new(ENCODER_CACHE_SIZE);
+    let mut compressed = ChunkCache::new(COMPRESSED_CACHE_SIZE);
+
+    if state.config.carousel {
+        info!("Sending every chunk over and over, ignoring requests");
+    }
+
+    while !state.token.is_cancelled() {
+        while let Ok(x) = receiver.try_recv() {
+            handle_message(state, &mut queue, scheduler.as_mut(), x);
+        }
+
+        // In carousel mode every chunk is queued again once all were sent.
+        if state.config.carousel && queue.is_empty() {
+            let now = Instant::now();
+            for chunk in 0..state.image.chunks.len() {
+                if let Some(size) = state.wire_size(chunk) {
+                    queue.insert(chunk, vec![(0, size)]);
+                    scheduler.request(chunk, None, now);
+                }
+            }
+            rounds += 1;
+            debug!("Starting carousel round {}", rounds);
+            state
+                .progress
+                .send_modify(|progress| progress.carousel_rounds = rounds);
+        }
+
+        let Some(next) = scheduler.next(Instant::now()) else {
+            select! {
+                biased;
+                _ = state.token.cancelled() => break,
+                x = receiver.recv() => if let Some(x) = x {
+                    sleep = Instant::now();
+                    handle_message(state, &mut queue, scheduler.as_mut(), x);
+                    continue;
+                } else {
+                    break
+                },
+            }
+        };
+
+        let Some(ranges) = queue.remove(&next) else {
+            continue;
+        };
+
+        // Chunks that left the window since they were requested are dropped.
+        if state.wire_size(next).is_none() {
+            continue;
+        }
+
+        // Encoders take far longer to build than their chunk takes to send, so
+        // the chunk is not even read when its encoder was kept.
+        let encoded = state.image.fec.is_some() && encoders.get(next).is_some();
+
+        let held;
+        let payload = match &state.stream {
+            _ if encoded => None,
+            Some(stream) => match stream.payload(next) {
+                Some(x) => {
+                    held = x;
+                    Some(Cow::Borrowed(&*held))
+                }
+                None => continue,
+            },
+            None => Some(
+                read_chunk(
+                    state,
+                    &mut file,
+                    &mut chunk_buf,
+                    &mut compressor,
+                    &mut compressed,
+                    next,
+                )
+                .await?,
+            ),
+        };
+
+        let mut fragments: Vec<(usize, Cow<[u8]>)> = Vec::new();
+
+        if let Some(fec) = &state.image.fec {
+            // Clients that have nothing of the chunk ask for all of it and get the
+            // source symbols plus the configured repair overhead. Clients that are
+            // only missing part of it get fresh repair symbols, which are useful to
+            // every receiver regardless of which fragments it already has.
+            let requested = ranges.iter().map(|&(_, end)| end).max().unwrap_or(0);
+            let repair_start = next_repair.entry(next).or_insert(0);
+            let encoder = match &payload {
+                Some(payload) => {
+                    let encoder = Encoder::new(payload, fec.transmission_info(payload.len()));
+                    // Source and intermediate symbols each take about the size of the chunk.
+                    encoders.insert(next, encoder, 2 * payload.len())
+                }
+                None => encoders.get(next).unwrap(),
+            };
+            let size = encoder.get_config().transfer_length() as usize;
+            let blocks = encoder.get_block_encoders();
+            let symbol_size = encoder.get_config().symbol_size() as usize;
+            let per_block = requested.div_ceil(symbol_size).div_ceil(blocks.len()) as u32;
+            let mut repair_end = *repair_start;
+
+            for block in blocks {
+                let source = block.source_packets();
+                let repair = fec.repair_symbols(source.len());
+                let packets = if requested >= size {
+                    repair_end = repair_end.max(*repair_start + repair);
+                    source
+                        .into_iter()
+                        .chain(block.repair_packets(*repair_start, repair))
+                        .collect()
+                } else {
+                    repair_end = repair_end.max(*repair_start + per_block + repair);
+                    block.repair_packets(*repair_start, per_block + repair)
+                };
+                fragments.extend(packets.into_iter().map(|packet| {
+                    let (payload_id, data) = packet.split();
+                    (
+                        u32::from_be_bytes(payload_id.serialize()) as usize,
+                        Cow::Owned(data),
+                    )
+                }));
+            }
+
+            *repair_start = repair_end;
+        } else {
+            let payload = payload
+                .as_deref()
+                .expect("Invalid global state (chunk was not read)");
+            let size = payload.len();
+            // Fragments are always cut on the same grid, so that a retransmitted
+            // fragment never partially overlaps with one that a client already has.
+            for (start, end) in ranges {
+                let mut count = start / max_fragment_size * max_fragment_size;
+                while count < end {
+                    let frag_size = (size - count).min(max_fragment_size);
+                    fragments.push((count, Cow::Borrowed(&payload[count..count + frag_size])));
+                    count += frag_size;
+                }
+            }
+        }
+
+        let mut bytes_sent = 0;
+        for (offset, data) in fragments {
+            bytes_sent += data.len() as u64;
+            let data = ChunkData {
+                session: state.session,
+                seq,
+                chunk: next,
+                offset,
+                data: &data,
+            };
+            let send = postcard::to_slice(&data, &mut send_buf)?;
+
+            sleep_until(sleep).await;
+            let sent = socket.send(send).await?;
+            ensure!(sent == send.len(), "Failed to send chunk fragment");
+            sleep += 8 * sent as u32 * Duration::from_secs(1)
+                / state.rate.load(Ordering::Relaxed).max(1);
+            seq += 1;
+        }
+
+        if let Some(stream) = &state.stream {
+            stream.mark_sent(next);
+        }
+
+        state.progress.send_modify(|progress| {
+            progress.bytes_sent += bytes_sent;
+            progress.rate = state.rate.load(Ordering::Relaxed);
+        });
+    }
+
+    Ok(())
+}
+
+/// Queues every chunk of the stream to be sent once as soon as it is read,
+/// as if all the clients requested it.
+async fn stream_pusher(state: &Arc<ServerState>, sender: Sender<Dispatch>) -> Result<()> {
+    let Some(stream) = &state.stream else {
+        return Ok(());
+    };
+
+    select! {
+        biased;
+        _ = state.token.cancelled() => return Ok(()),
+        _ = stream.wait_for_clients() => {},
+    }
+    info!("Clients joined, starting the stream");
+
+    let mut produced = stream.produced();
+    let mut next = 0;
+
+    loop {
+        let count = *produced.borrow_and_update();
+        for chunk in next..count {
+            if let Some(len) = stream.wire_size(chunk)
+                && sender
+                    .send(Dispatch::Request(ChunkRequest {
+                        range: FragmentRange {
+                            chunk,
+                            offset: 0,
+                            len,
+                        },
+                        requester: None,
+                    }))
+                    .await
+                    .is_err()
+            {
+                return Ok(());
+            }
+        }
+        next = count;
+
+        select! {
+            biased;
+            _ = state.token.cancelled() => return Ok(()),
+            _ = produced.changed() => {},
+        }
+    }
+}
+
+pub async fn chunk_request_server(
+    state: Arc<ServerState>,
+    scheduler: Box<dyn Scheduler>,
+) -> Result<()> {
+    let bind_address = match state.unicast {
+        IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, 0)),
+        IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            0,
+            0,
+            if ip.is_unicast_link_local() {
+                state.interface.index
+            } else {
+                0
+            },
+        )),
+    };
+
+    let (sx, rx) = channel::<Dispatch>(256);
+
+    try_join!(
+        request_listener(&state, bind_address, sx.clone()),
+        chunk_dispatcher(&state, bind_address, rx, scheduler),
+        stream_pusher(&state, sx),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_disjoint_ranges() {
+        let mut ranges = Vec::new();
+        insert_range(&mut ranges, 50, 60);
+        insert_range(&mut ranges, 10, 20);
+        insert_range(&mut ranges, 80, 90);
+        assert_eq!(ranges, vec![(10, 20), (50, 60), (80, 90)]);
+    }
+
+    #[test]
+    fn merge_overlapping_ranges() {
+        let mut ranges = vec![(10, 20), (50, 60), (80, 90)];
+        insert_range(&mut ranges, 15, 55);
+        assert_eq!(ranges, vec![(10, 60), (80, 90)]);
+        insert_range(&mut ranges, 0, 100);
+        assert_eq!(ranges, vec![(0, 100)]);
+        insert_range(&mut ranges, 30, 40);
+        assert_eq!(ranges, vec![(0, 100)]);
+    }
+
+    #[test]
+    fn merge_adjacent_ranges() {
+        let mut ranges = vec![(10, 20), (30, 40)];
+        insert_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, vec![(10, 40)]);
+        insert_range(&mut ranges, 40, 50);
+        insert_range(&mut ranges, 0, 10);
+        assert_eq!(ranges, vec![(0, 50)]);
+    }
+}
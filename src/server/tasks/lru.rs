@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+
+/// Values derived from chunks, such as their encoders, kept around while
+/// they fit in a budget of bytes and evicted least recently used first.
+pub struct ChunkCache<T> {
+    /// Most recently used last, with the size each value accounts for.
+    entries: VecDeque<(usize, T, usize)>,
+    size: usize,
+    capacity: usize,
+}
+
+impl<T> ChunkCache<T> {
+    pub fn new(capacity: usize) -> ChunkCache<T> {
+        ChunkCache {
+            entries: VecDeque::new(),
+            size: 0,
+            capacity,
+        }
+    }
+
+    /// Returns the value of `chunk`, marking it as the most recently used.
+    pub fn get(&mut self, chunk: usize) -> Option<&T> {
+        let position = self.entries.iter().position(|&(x, _, _)| x == chunk)?;
+        let entry = self.entries.remove(position).unwrap();
+        self.entries.push_back(entry);
+        self.entries.back().map(|(_, value, _)| value)
+    }
+
+    /// Stores the value of `chunk`, evicting the least recently used ones to
+    /// make room for its `size`. The latest value is always kept, even if it
+    /// does not fit on its own.
+    pub fn insert(&mut self, chunk: usize, value: T, size: usize) -> &T {
+        if let Some(position) = self.entries.iter().position(|&(x, _, _)| x == chunk) {
+            let (_, _, old) = self.entries.remove(position).unwrap();
+            self.size -= old;
+        }
+        while self.size + size > self.capacity
+            && let Some((_, _, old)) = self.entries.pop_front()
+        {
+            self.size -= old;
+        }
+        self.size += size;
+        self.entries.push_back((chunk, value, size));
+        self.entries.back().map(|(_, value, _)| value).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ChunkCache::new(30);
+        cache.insert(1, "a", 10);
+        cache.insert(2, "b", 10);
+        cache.insert(3, "c", 10);
+        assert_eq!(cache.get(1), Some(&"a"));
+        cache.insert(4, "d", 10);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(&"a"));
+        assert_eq!(cache.get(3), Some(&"c"));
+        assert_eq!(cache.get(4), Some(&"d"));
+    }
+
+    #[test]
+    fn keeps_oversized_value() {
+        let mut cache = ChunkCache::new(10);
+        cache.insert(1, "a", 5);
+        cache.insert(2, "b", 50);
+        assert_eq!(cache.get(1), None);
+        assert_eq!(cache.get(2), Some(&"b"));
+        cache.insert(2, "c", 5);
+        assert_eq!(cache.get(2), Some(&"c"));
+        cache.insert(3, "d", 5);
+        assert_eq!(cache.get(2), Some(&"c"));
+    }
+}
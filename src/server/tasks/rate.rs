@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use log::{debug, warn};
+use tokio::time::Instant;
+
+use crate::{ClientId, ReceptionReport};
+
+/// Receivers that have not reported for this long are forgotten.
+const RECEIVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct ReceiverStatus {
+    /// Where the last report came from, for the logs.
+    address: SocketAddr,
+    report: ReceptionReport,
+    last_report: Instant,
+    fresh: bool,
+    slow_since: Option<Instant>,
+    dropped: bool,
+}
+
+/// Adapts the sending rate to the loss reported by the receivers.
+///
+/// The rate starts at the floor and grows exponentially until the first loss
+/// is reported, then additively. Whenever the worst receiver reports more
+/// loss than the target the rate is scaled down to what that receiver is
+/// able to get through.
+pub struct RateController {
+    floor: u32,
+    ceiling: u32,
+    target_loss: f32,
+    drop_after: Option<Duration>,
+    rate: u32,
+    slow_start: bool,
+    receivers: BTreeMap<ClientId, ReceiverStatus>,
+}
+
+impl RateController {
+    pub fn new(
+        floor: u32,
+        ceiling: u32,
+        target_loss: f32,
+        drop_after: Option<Duration>,
+    ) -> RateController {
+        RateController {
+            floor,
+            ceiling,
+            target_loss,
+            drop_after,
+            rate: floor,
+            slow_start: true,
+            receivers: BTreeMap::new(),
+        }
+    }
+
+    pub fn report(
+        &mut self,
+        client: ClientId,
+        from: SocketAddr,
+        report: ReceptionReport,
+        now: Instant,
+    ) {
+        let status = self.receivers.entry(client).or_insert(ReceiverStatus {
+            address: from,
+            report,
+            last_report: now,
+            fresh: true,
+            slow_since: None,
+            dropped: false,
+        });
+        status.address = from;
+        status.report = report;
+        status.last_report = now;
+        status.fresh = true;
+    }
+
+    /// Computes the new sending rate from the reports received since the last call.
+    pub fn adjust(&mut self, now: Instant) -> u32 {
+        self.receivers
+            .retain(|_, status| now - status.last_report < RECEIVER_TIMEOUT);
+
+        let worst = self
+            .receivers
+            .iter()
+            .filter(|(_, status)| status.fresh && !status.dropped)
+            .max_by(|(_, a), (_, b)| a.report.loss.total_cmp(&b.report.loss))
+            .map(|(_, status)| (status.address, status.report));
+
+        let Some((worst_addr, worst)) = worst else {
+            return self.rate;
+        };
+
+        if worst.loss > self.target_loss {
+            self.slow_start = false;
+            let scale = (1.0 - worst.loss).max(0.5) as f64;
+            self.rate = ((self.rate as f64 * scale) as u32).max(self.floor);
+            debug!(
+                "Receiver {} reports {:.1}% loss at {} bits/s, slowing down to {} bits/s",
+                worst_addr,
+                worst.loss * 100.0,
+                worst.goodput,
+                self.rate
+            );
+        } else if self.slow_start {
+            self.rate = self.rate.saturating_add(self.rate / 4).min(self.ceiling);
+        } else {
+            self.rate = self
+                .rate
+                .saturating_add(self.ceiling / 50)
+                .min(self.ceiling);
+        }
+
+        for status in self.receivers.values_mut() {
+            if !status.fresh || status.dropped {
+                continue;
+            }
+            status.fresh = false;
+
+            if status.report.loss <= self.target_loss || self.rate > self.floor {
+                status.slow_since = None;
+                continue;
+            }
+
+            let since = *status.slow_since.get_or_insert(now);
+            if let Some(drop_after) = self.drop_after
+                && now - since >= drop_after
+            {
+                warn!(
+                    "Receiver {} cannot keep up with the minimum rate, ignoring its reports",
+                    status.address
+                );
+                status.dropped = true;
+            }
+        }
+
+        self.rate
+    }
+}